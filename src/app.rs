@@ -1,10 +1,30 @@
+use std::time::Duration;
+
 use cpal::{
     traits::{DeviceTrait, HostTrait},
-    SupportedStreamConfigRange,
+    Device, SampleRate, SupportedStreamConfig, SupportedStreamConfigRange,
 };
 use egui::Slider;
 
-use crate::{audio_io::AudioIO, plugins_container::PluginsContainer};
+use crate::{
+    audio_io::AudioIO,
+    keyboard::PianoKeyboard,
+    midi_file::{MidiFile, MidiFileError},
+    midi_player::{MidiPlayer, PlayerSettings},
+    midi_recorder::MidiRecorder,
+    plugin_bundle::{describe_bundle, PluginDescription},
+    plugin_host::PluginLoadError,
+    plugin_processor::PluginId,
+    plugins_container::PluginsContainer,
+    routing_graph::{NodeId, NodeKind, RoutingError, RoutingGraph},
+    scan_cache::ScanCache,
+    scanner::{CatalogScan, PluginCatalog},
+    transport::{TransportCommand, TransportSettings},
+};
+
+const PREFERRED_SAMPLE_RATE: u32 = 48_000;
+const PROCESSING_SAMPLE_RATES: [u32; 4] = [44_100, 48_000, 88_200, 96_000];
+const DEFAULT_BPM: f64 = 120.0;
 
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -23,25 +43,81 @@ pub struct TemplateApp {
     #[serde(skip)]
     selected_audio_device: String,
     #[serde(skip)]
+    selected_input_device: String,
+    #[serde(skip)]
     selected_input_config: String,
     #[serde(skip)]
     selected_output_config: String,
     #[serde(skip)]
+    processing_sample_rate: Option<u32>,
+    #[serde(skip)]
     audio_io: AudioIO,
+    #[serde(skip)]
+    audio_error: Option<String>,
+    #[serde(skip)]
+    opened_bundle: Option<(String, Vec<PluginDescription>)>,
+    #[serde(skip)]
+    load_error: Option<PluginLoadError>,
+    #[serde(skip)]
+    catalog: PluginCatalog,
+    catalog_filter: String,
+    scan_cache: ScanCache,
+    #[serde(skip)]
+    scan: Option<CatalogScan>,
+    #[serde(skip)]
+    new_edge: (NodeId, NodeId, f32),
+    #[serde(skip)]
+    routing_error: Option<RoutingError>,
+    #[serde(skip)]
+    keyboard: PianoKeyboard,
+    #[serde(skip)]
+    keyboard_plugin: Option<PluginId>,
+    #[serde(skip)]
+    midi_file_path: Option<String>,
+    #[serde(skip)]
+    player_settings: PlayerSettings,
+    #[serde(skip)]
+    midi_error: Option<MidiFileError>,
+    transport_settings: TransportSettings,
+    #[serde(skip)]
+    recorder: Option<MidiRecorder>,
+    #[serde(skip)]
+    record_source: Option<PluginId>,
 }
 
 impl Default for TemplateApp {
     fn default() -> Self {
+        let audio_io = AudioIO::init();
+
         Self {
             // Example stuff:
             label: "Hello World!".to_owned(),
             value: 2.7,
             plugins_to_remove: vec![],
-            plugins_container: PluginsContainer::init(),
+            plugins_container: PluginsContainer::init(audio_io.plugin_audio_configuration()),
             selected_audio_device: String::new(),
+            selected_input_device: String::new(),
             selected_input_config: String::new(),
             selected_output_config: String::new(),
-            audio_io: AudioIO::init(),
+            processing_sample_rate: None,
+            audio_io,
+            audio_error: None,
+            opened_bundle: None,
+            load_error: None,
+            catalog: PluginCatalog::default(),
+            catalog_filter: String::new(),
+            scan_cache: ScanCache::default(),
+            scan: None,
+            new_edge: (RoutingGraph::INPUT, RoutingGraph::OUTPUT, 1.0),
+            routing_error: None,
+            keyboard: PianoKeyboard::default(),
+            keyboard_plugin: None,
+            midi_file_path: None,
+            player_settings: PlayerSettings::default(),
+            midi_error: None,
+            transport_settings: TransportSettings::default(),
+            recorder: None,
+            record_source: None,
         }
     }
 }
@@ -54,11 +130,590 @@ impl TemplateApp {
 
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
-        if let Some(storage) = cc.storage {
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+        let mut app: Self = cc
+            .storage
+            .and_then(|storage| eframe::get_value(storage, eframe::APP_KEY))
+            .unwrap_or_default();
+
+        app.start_scan(&cc.egui_ctx);
+        app.audio_io.set_transport_settings(app.transport_settings);
+
+        app
+    }
+
+    fn start_scan(&mut self, ctx: &egui::Context) {
+        if self.scan.is_some() {
+            return;
+        }
+
+        let ctx = ctx.clone();
+        self.scan = Some(CatalogScan::start(self.scan_cache.clone(), move || {
+            ctx.request_repaint()
+        }));
+    }
+
+    /// Takes the results of a finished scan, and saves the cache right away
+    /// so a long scan isn't lost if the app doesn't exit cleanly.
+    fn poll_scan(&mut self, frame: &mut eframe::Frame) {
+        if !self.scan.as_ref().map_or(false, CatalogScan::is_finished) {
+            return;
+        }
+
+        let Some((catalog, scan_cache)) = self.scan.take().and_then(CatalogScan::join) else {
+            return;
+        };
+        self.catalog = catalog;
+        self.scan_cache = scan_cache;
+
+        if let Some(storage) = frame.storage_mut() {
+            eframe::App::save(self, storage);
+            storage.flush();
+        }
+    }
+
+    /// Reopens the stream on the selected device and config, and makes the
+    /// plugins follow the new processing sample rate.
+    fn apply_output_settings(&mut self) {
+        if let Some((device, config)) =
+            find_output_config(&self.selected_audio_device, &self.selected_output_config)
+        {
+            match self.audio_io.reopen(&device, config) {
+                // The player went away with the old stream
+                Ok(()) => self.load_midi_file(),
+                Err(err) => self.audio_error = Some(err),
+            }
+        }
+
+        self.audio_io
+            .set_processing_sample_rate(self.processing_sample_rate);
+        self.plugins_container
+            .set_audio_configuration(self.audio_io.plugin_audio_configuration());
+        self.plugins_container.invalidate_routing();
+    }
+
+    fn input_device_name(&self) -> &str {
+        if self.selected_input_device.is_empty() {
+            &self.selected_audio_device
+        } else {
+            &self.selected_input_device
+        }
+    }
+
+    fn apply_input_settings(&mut self) {
+        self.audio_io.close_input();
+        self.audio_error = None;
+
+        if self.selected_input_config.is_empty() {
+            return;
+        }
+
+        let Some((device, config)) = find_input_config(
+            self.input_device_name(),
+            &self.selected_input_config,
+            self.audio_io.sample_rate(),
+        ) else {
+            return;
+        };
+
+        if let Err(err) = self.audio_io.open_input(&device, config) {
+            self.audio_error = Some(err);
+        }
+    }
+
+    /// Plays the selected instrument from the on-screen keyboard.
+    fn keyboard_ui(&mut self, ui: &mut egui::Ui) {
+        let instruments: Vec<_> = self
+            .plugins_container
+            .plugins
+            .iter()
+            .filter(|plugin| plugin.note_dialect().is_some())
+            .map(|plugin| (plugin.id, plugin.name().to_owned()))
+            .collect();
+
+        if !instruments
+            .iter()
+            .any(|(id, _)| Some(*id) == self.keyboard_plugin)
+        {
+            self.keyboard_plugin = instruments.first().map(|(id, _)| *id);
+        }
+
+        let Some(plugin_id) = self.keyboard_plugin else {
+            ui.label("Load an instrument to play it from the keyboard");
+            return;
+        };
+
+        let selected_text = instruments
+            .iter()
+            .find(|(id, _)| *id == plugin_id)
+            .map(|(_, name)| name.clone())
+            .unwrap_or_default();
+        let mut selected = plugin_id;
+
+        egui::ComboBox::from_label("Keyboard plays")
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                for (id, name) in &instruments {
+                    ui.selectable_value(&mut selected, *id, name);
+                }
+            });
+
+        if selected != plugin_id {
+            for note in self.keyboard.choke_all() {
+                self.audio_io.send_note(plugin_id, note);
+            }
+            self.keyboard_plugin = Some(selected);
+        }
+
+        for note in self.keyboard.ui(ui) {
+            self.audio_io.send_note(selected, note);
+        }
+    }
+
+    /// Hands the selected file to a new player on the audio thread.
+    fn load_midi_file(&mut self) {
+        let Some(path) = &self.midi_file_path else {
+            return;
+        };
+
+        match MidiFile::load(path) {
+            Ok(file) => {
+                self.midi_error = None;
+                self.audio_io
+                    .set_midi_player(Some(MidiPlayer::new(file, self.player_settings)));
+            }
+            Err(err) => {
+                self.midi_error = Some(err);
+                self.midi_file_path = None;
+                self.audio_io.set_midi_player(None);
+            }
+        }
+    }
+
+    fn midi_player_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("Open MIDI file").clicked() {
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("Standard MIDI File", &["mid", "midi"])
+                    .pick_file()
+                {
+                    self.midi_file_path = Some(path.display().to_string());
+                    self.load_midi_file();
+                }
+            }
+
+            if let Some(path) = &self.midi_file_path {
+                ui.label(path);
+            }
+        });
+
+        if let Some(err) = &self.midi_error {
+            ui.colored_label(ui.visuals().error_fg_color, err.to_string());
+        }
+
+        if self.midi_file_path.is_none() {
+            return;
         }
 
-        Default::default()
+        ui.label("Plays along with the transport");
+
+        let mut settings = self.player_settings;
+        let instruments: Vec<_> = self
+            .plugins_container
+            .plugins
+            .iter()
+            .filter(|plugin| plugin.note_dialect().is_some())
+            .map(|plugin| (plugin.id, plugin.name().to_owned()))
+            .collect();
+
+        if !instruments
+            .iter()
+            .any(|(id, _)| Some(*id) == settings.target)
+        {
+            settings.target = instruments.first().map(|(id, _)| *id);
+        }
+
+        let selected_text = instruments
+            .iter()
+            .find(|(id, _)| Some(*id) == settings.target)
+            .map_or_else(|| "Nothing".to_owned(), |(_, name)| name.clone());
+
+        egui::ComboBox::from_label("MIDI file plays")
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                for (id, name) in &instruments {
+                    ui.selectable_value(&mut settings.target, Some(*id), name);
+                }
+            });
+
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut settings.is_looping, "Loop");
+
+            let mut has_tempo = settings.tempo.is_some();
+            ui.checkbox(&mut has_tempo, "Tempo");
+            settings.tempo = has_tempo.then(|| settings.tempo.unwrap_or(DEFAULT_BPM));
+
+            if let Some(tempo) = &mut settings.tempo {
+                ui.add(
+                    egui::DragValue::new(tempo)
+                        .clamp_range(20.0..=300.0)
+                        .suffix(" BPM"),
+                );
+            }
+        });
+
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Channels");
+            for channel in 0..16 {
+                let mut is_enabled = settings.channels & (1 << channel) != 0;
+                if ui
+                    .checkbox(&mut is_enabled, (channel + 1).to_string())
+                    .changed()
+                {
+                    settings.channels ^= 1 << channel;
+                }
+            }
+        });
+
+        if settings != self.player_settings {
+            self.player_settings = settings;
+            self.audio_io.set_player_settings(settings);
+        }
+    }
+
+    fn transport_ui(&mut self, ui: &mut egui::Ui) {
+        let position = self.audio_io.transport_position();
+        let (beats, is_playing) = (position.beats(), position.is_playing());
+        let mut settings = self.transport_settings;
+
+        ui.horizontal(|ui| {
+            let play_label = if is_playing { "⏸" } else { "▶" };
+            if ui.button(play_label).clicked() {
+                let command = if is_playing {
+                    TransportCommand::Stop
+                } else {
+                    TransportCommand::Play
+                };
+                self.audio_io.send_transport_command(command);
+            }
+            if ui.button("⏮").clicked() {
+                self.audio_io
+                    .send_transport_command(TransportCommand::Seek(0.0));
+            }
+
+            let beats_per_bar = settings.beats_per_bar();
+            let seconds = settings.beats_to_seconds(beats);
+            ui.monospace(format!(
+                "{:>3}.{} {:02}:{:04.1}",
+                (beats / beats_per_bar) as u32 + 1,
+                (beats % beats_per_bar) as u32 + 1,
+                (seconds / 60.0) as u32,
+                seconds % 60.0
+            ));
+
+            ui.separator();
+            ui.add(
+                egui::DragValue::new(&mut settings.tempo)
+                    .clamp_range(20.0..=300.0)
+                    .speed(0.1)
+                    .suffix(" BPM"),
+            );
+
+            ui.add(egui::DragValue::new(&mut settings.numerator).clamp_range(1..=32));
+            ui.label("/");
+            egui::ComboBox::from_id_source("time_signature_denominator")
+                .width(40.0)
+                .selected_text(settings.denominator.to_string())
+                .show_ui(ui, |ui| {
+                    for denominator in [1, 2, 4, 8, 16] {
+                        ui.selectable_value(
+                            &mut settings.denominator,
+                            denominator,
+                            denominator.to_string(),
+                        );
+                    }
+                });
+
+            ui.separator();
+            ui.checkbox(&mut settings.is_looping, "Loop");
+            ui.add_enabled(
+                settings.is_looping,
+                egui::DragValue::new(&mut settings.loop_start)
+                    .clamp_range(0.0..=f64::MAX)
+                    .prefix("from beat "),
+            );
+            ui.add_enabled(
+                settings.is_looping,
+                egui::DragValue::new(&mut settings.loop_end)
+                    .clamp_range(settings.loop_start..=f64::MAX)
+                    .prefix("to "),
+            );
+        });
+
+        if settings != self.transport_settings {
+            self.transport_settings = settings;
+            self.audio_io.set_transport_settings(settings);
+        }
+    }
+
+    /// Records the notes plugins play and saves them to a MIDI file.
+    fn recorder_ui(&mut self, ui: &mut egui::Ui) {
+        let plugin_name = |id: Option<PluginId>| {
+            self.plugins_container
+                .plugins
+                .iter()
+                .find(|plugin| Some(plugin.id) == id)
+                .map_or_else(
+                    || "All plugins".to_owned(),
+                    |plugin| plugin.name().to_owned(),
+                )
+        };
+
+        let Some(recorder) = &self.recorder else {
+            ui.horizontal(|ui| {
+                let mut source = self.record_source;
+                egui::ComboBox::from_label("Record from")
+                    .selected_text(plugin_name(source))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut source, None, "All plugins");
+                        for plugin in &self.plugins_container.plugins {
+                            ui.selectable_value(&mut source, Some(plugin.id), plugin.name());
+                        }
+                    });
+                self.record_source = source;
+
+                if ui.button("Record").clicked() {
+                    self.recorder = Some(MidiRecorder::new(self.record_source));
+                }
+            });
+            return;
+        };
+
+        let mut stop = false;
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "Recording {}: {} events",
+                plugin_name(self.record_source),
+                recorder.events_count()
+            ));
+            stop = ui.button("Stop and save").clicked();
+        });
+        if !self.audio_io.transport_position().is_playing() {
+            ui.weak("Plugins are only recorded while the transport plays");
+        }
+
+        if !stop {
+            return;
+        }
+
+        let Some(recorder) = self.recorder.take() else {
+            return;
+        };
+        if let Some(path) = rfd::FileDialog::new()
+            .add_filter("Standard MIDI File", &["mid"])
+            .save_file()
+        {
+            if let Err(err) = recorder.into_midi_file().save(&path.display().to_string()) {
+                self.midi_error = Some(err);
+            }
+        }
+    }
+
+    fn node_name(&self, id: NodeId) -> String {
+        let graph = self.plugins_container.graph();
+
+        match graph
+            .nodes()
+            .iter()
+            .find(|node| node.id == id)
+            .map(|node| node.kind)
+        {
+            Some(NodeKind::Input) => "Input".to_owned(),
+            Some(NodeKind::Output) => "Output".to_owned(),
+            Some(NodeKind::Bus) => format!("Bus #{id}"),
+            Some(NodeKind::Plugin(plugin_id)) => self
+                .plugins_container
+                .plugins
+                .iter()
+                .find(|plugin| plugin.id == plugin_id)
+                .map_or_else(
+                    || format!("Plugin #{plugin_id}"),
+                    |plugin| plugin.name().to_owned(),
+                ),
+            None => format!("#{id}"),
+        }
+    }
+
+    fn routing_ui(&mut self, ui: &mut egui::Ui) {
+        let node_ids: Vec<_> = self
+            .plugins_container
+            .graph()
+            .nodes()
+            .iter()
+            .map(|node| node.id)
+            .collect();
+
+        ui.horizontal(|ui| {
+            if ui.button("Add bus").clicked() {
+                self.plugins_container.graph_mut().add_bus();
+            }
+
+            ui.menu_button("Add plugin", |ui| {
+                for index in 0..self.plugins_container.plugins.len() {
+                    let plugin = &self.plugins_container.plugins[index];
+                    let plugin_id = plugin.id;
+                    if self
+                        .plugins_container
+                        .graph()
+                        .plugin_node(plugin_id)
+                        .is_some()
+                    {
+                        continue;
+                    }
+
+                    if ui.button(plugin.name()).clicked() {
+                        self.plugins_container.graph_mut().add_plugin(plugin_id);
+                        ui.close_menu();
+                    }
+                }
+            });
+        });
+
+        ui.separator();
+        ui.label("Nodes:");
+        for id in &node_ids {
+            ui.horizontal(|ui| {
+                if *id != RoutingGraph::INPUT
+                    && *id != RoutingGraph::OUTPUT
+                    && ui.button("-").clicked()
+                {
+                    self.plugins_container.graph_mut().remove_node(*id);
+                }
+                ui.label(self.node_name(*id));
+            });
+        }
+
+        ui.separator();
+        ui.label("Connections:");
+        let mut edge_to_remove = None;
+        let edges: Vec<_> = self
+            .plugins_container
+            .graph()
+            .edges()
+            .iter()
+            .map(|edge| (edge.from, edge.to, edge.gain))
+            .collect();
+
+        for (index, (from, to, gain)) in edges.into_iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui.button("-").clicked() {
+                    edge_to_remove = Some((from, to));
+                }
+                ui.label(format!("{} → {}", self.node_name(from), self.node_name(to)));
+
+                let mut gain = gain;
+                if ui
+                    .add(Slider::new(&mut gain, 0.0..=2.0).text("gain"))
+                    .changed()
+                {
+                    self.plugins_container.graph_mut().edges_mut()[index].gain = gain;
+                }
+            });
+        }
+
+        if let Some((from, to)) = edge_to_remove {
+            self.plugins_container.graph_mut().disconnect(from, to);
+        }
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            let node_names: Vec<_> = node_ids
+                .iter()
+                .map(|id| (*id, self.node_name(*id)))
+                .collect();
+
+            for (label, selected) in [("From", &mut self.new_edge.0), ("To", &mut self.new_edge.1)]
+            {
+                let selected_text = node_names
+                    .iter()
+                    .find(|(id, _)| id == selected)
+                    .map(|(_, name)| name.clone())
+                    .unwrap_or_default();
+
+                egui::ComboBox::from_label(label)
+                    .selected_text(selected_text)
+                    .show_ui(ui, |ui| {
+                        for (id, name) in &node_names {
+                            ui.selectable_value(selected, *id, name);
+                        }
+                    });
+            }
+
+            ui.add(Slider::new(&mut self.new_edge.2, 0.0..=2.0).text("gain"));
+
+            if ui.button("Connect").clicked() {
+                let (from, to, gain) = self.new_edge;
+                self.routing_error = self
+                    .plugins_container
+                    .graph_mut()
+                    .connect(from, to, gain)
+                    .err();
+            }
+        });
+
+        ui.separator();
+        ui.label("Sidechains:");
+        let node_names: Vec<_> = node_ids
+            .iter()
+            .filter(|id| **id != RoutingGraph::OUTPUT)
+            .map(|id| (*id, self.node_name(*id)))
+            .collect();
+        let mut sidechain_to_set = None;
+
+        for plugin in &self.plugins_container.plugins {
+            let Some(node) = self.plugins_container.graph().plugin_node(plugin.id) else {
+                continue;
+            };
+
+            for (port_index, port) in plugin.input_ports.iter().enumerate() {
+                if port.is_main() {
+                    continue;
+                }
+
+                let source = self.plugins_container.graph().sidechain(node, port_index);
+                let selected_text = node_names
+                    .iter()
+                    .find(|(id, _)| Some(*id) == source)
+                    .map_or_else(|| "None".to_owned(), |(_, name)| name.clone());
+
+                egui::ComboBox::from_label(format!("{}: {}", plugin.name(), port.name))
+                    .selected_text(selected_text)
+                    .show_ui(ui, |ui| {
+                        if ui.selectable_label(source.is_none(), "None").clicked() {
+                            sidechain_to_set = Some((node, port_index, None));
+                        }
+
+                        for (id, name) in &node_names {
+                            if *id != node
+                                && ui.selectable_label(source == Some(*id), name).clicked()
+                            {
+                                sidechain_to_set = Some((node, port_index, Some(*id)));
+                            }
+                        }
+                    });
+            }
+        }
+
+        if let Some((node, port, source)) = sidechain_to_set {
+            self.routing_error = self
+                .plugins_container
+                .graph_mut()
+                .set_sidechain(node, port, source)
+                .err();
+        }
+
+        if let Some(err) = &self.routing_error {
+            ui.colored_label(ui.visuals().error_fg_color, err.to_string());
+        }
     }
 }
 
@@ -69,10 +724,30 @@ impl eframe::App for TemplateApp {
     }
 
     /// Called each time the UI needs repainting, which may be many times per second.
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
 
+        self.poll_scan(frame);
+
+        self.plugins_container.sync(&mut self.audio_io);
+        if let Some(err) = self.plugins_container.take_error() {
+            self.load_error = Some(err);
+        }
+
+        let param_outputs = self.audio_io.poll_param_outputs();
+        if !param_outputs.is_empty() {
+            self.plugins_container.apply_param_outputs(&param_outputs);
+            ctx.request_repaint();
+        }
+
+        let captured = self.audio_io.poll_captured_midi();
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&captured);
+        }
+        // Keep polling the audio thread even when there's no user input
+        ctx.request_repaint_after(Duration::from_millis(50));
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
 
@@ -90,8 +765,13 @@ impl eframe::App for TemplateApp {
 
                 egui::widgets::global_dark_light_mode_buttons(ui);
             });
+
+            self.transport_ui(ui);
         });
 
+        let mut is_output_changed = false;
+        let mut is_input_changed = false;
+
         egui::SidePanel::right("settings").show(ctx, |ui| {
             let host = cpal::default_host();
 
@@ -102,11 +782,52 @@ impl eframe::App for TemplateApp {
                     .show_ui(ui, |ui| {
                         for device in host.devices().unwrap() {
                             let device_name = device.name().unwrap();
-                            ui.selectable_value(
-                                &mut self.selected_audio_device,
-                                device_name.clone(),
-                                device_name,
-                            );
+                            if ui
+                                .selectable_value(
+                                    &mut self.selected_audio_device,
+                                    device_name.clone(),
+                                    device_name,
+                                )
+                                .changed()
+                            {
+                                // Configs of the previous device don't apply anymore
+                                self.selected_input_config.clear();
+                                self.selected_output_config.clear();
+                                is_output_changed = true;
+                            }
+                        }
+                    });
+
+                egui::ComboBox::from_label("Input device")
+                    .width(350.0)
+                    .selected_text(if self.selected_input_device.is_empty() {
+                        "Same as output"
+                    } else {
+                        &self.selected_input_device
+                    })
+                    .show_ui(ui, |ui| {
+                        let mut changed = ui
+                            .selectable_value(
+                                &mut self.selected_input_device,
+                                String::new(),
+                                "Same as output",
+                            )
+                            .changed();
+
+                        for device in host.input_devices().unwrap() {
+                            let device_name = device.name().unwrap();
+                            changed |= ui
+                                .selectable_value(
+                                    &mut self.selected_input_device,
+                                    device_name.clone(),
+                                    device_name,
+                                )
+                                .changed();
+                        }
+
+                        if changed {
+                            self.selected_input_config.clear();
+                            is_input_changed = true;
                         }
                     });
 
@@ -114,22 +835,36 @@ impl eframe::App for TemplateApp {
                     .width(350.0)
                     .selected_text(&self.selected_input_config)
                     .show_ui(ui, |ui| {
+                        is_input_changed |= ui
+                            .selectable_value(
+                                &mut self.selected_input_config,
+                                String::new(),
+                                "No input",
+                            )
+                            .changed();
+
                         if let Some(device) = host
                             .devices()
                             .unwrap()
-                            .find(|device| device.name().unwrap() == self.selected_audio_device)
+                            .find(|device| device.name().unwrap() == self.input_device_name())
                         {
                             for input_config in device.supported_input_configs().unwrap() {
                                 let formatted = format_stream_config(&input_config);
-                                ui.selectable_value(
-                                    &mut self.selected_input_config,
-                                    formatted.clone(),
-                                    formatted,
-                                );
+                                is_input_changed |= ui
+                                    .selectable_value(
+                                        &mut self.selected_input_config,
+                                        formatted.clone(),
+                                        formatted,
+                                    )
+                                    .changed();
                             }
                         }
                     });
 
+                if let Some(err) = &self.audio_error {
+                    ui.colored_label(ui.visuals().error_fg_color, err);
+                }
+
                 egui::ComboBox::from_label("Outputs")
                     .width(350.0)
                     .selected_text(&self.selected_output_config)
@@ -139,19 +874,157 @@ impl eframe::App for TemplateApp {
                             .unwrap()
                             .find(|device| device.name().unwrap() == self.selected_audio_device)
                         {
-                            for input_config in device.supported_output_configs().unwrap() {
-                                let formatted = format_stream_config(&input_config);
-                                ui.selectable_value(
-                                    &mut self.selected_output_config,
-                                    formatted.clone(),
-                                    formatted,
-                                );
+                            for output_config in device.supported_output_configs().unwrap() {
+                                let formatted = format_stream_config(&output_config);
+                                is_output_changed |= ui
+                                    .selectable_value(
+                                        &mut self.selected_output_config,
+                                        formatted.clone(),
+                                        formatted,
+                                    )
+                                    .changed();
                             }
                         }
                     });
+
+                egui::ComboBox::from_label("Processing rate")
+                    .width(350.0)
+                    .selected_text(format_processing_sample_rate(self.processing_sample_rate))
+                    .show_ui(ui, |ui| {
+                        for sample_rate in
+                            std::iter::once(None).chain(PROCESSING_SAMPLE_RATES.map(Some))
+                        {
+                            is_output_changed |= ui
+                                .selectable_value(
+                                    &mut self.processing_sample_rate,
+                                    sample_rate,
+                                    format_processing_sample_rate(sample_rate),
+                                )
+                                .changed();
+                        }
+                    });
             })
         });
 
+        if is_output_changed {
+            self.apply_output_settings();
+        }
+
+        // Reopening the output drops the input stream as well
+        if is_input_changed || is_output_changed {
+            self.apply_input_settings();
+        }
+
+        egui::SidePanel::left("catalog").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.heading("Plugins");
+                let is_scanning = self.scan.is_some();
+                if ui
+                    .add_enabled(!is_scanning, egui::Button::new("Rescan"))
+                    .clicked()
+                {
+                    self.start_scan(ctx);
+                }
+                if ui
+                    .add_enabled(!is_scanning, egui::Button::new("Clear cache"))
+                    .clicked()
+                {
+                    self.scan_cache.clear();
+                    self.start_scan(ctx);
+                }
+                if is_scanning {
+                    ui.spinner();
+                }
+            });
+            ui.text_edit_singleline(&mut self.catalog_filter);
+
+            let filter = self.catalog_filter.to_lowercase();
+
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for entry in &self.catalog.entries {
+                    let description = &entry.description;
+                    if !description.name.to_lowercase().contains(&filter)
+                        && !description.vendor.to_lowercase().contains(&filter)
+                    {
+                        continue;
+                    }
+
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        if ui.button("+").clicked() {
+                            if let Err(err) =
+                                self.plugins_container.load(&entry.path, &description.id)
+                            {
+                                self.load_error = Some(err);
+                            }
+                        }
+                        ui.vertical(|ui| {
+                            ui.strong(&description.name);
+                            ui.label(format!("{} {}", description.vendor, description.version));
+                            ui.small(&description.id);
+                            ui.small(description.features.join(", "));
+                        });
+                    });
+                }
+
+                for (path, reason) in &self.catalog.failed {
+                    ui.separator();
+                    ui.colored_label(
+                        ui.visuals().error_fg_color,
+                        format!("Failed: {path}: {reason}"),
+                    );
+                }
+            });
+        });
+
+        let mut dismiss_error = false;
+        if let Some(err) = &self.load_error {
+            egui::TopBottomPanel::bottom("load_error").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.colored_label(ui.visuals().error_fg_color, err.to_string());
+                    dismiss_error = ui.button("Dismiss").clicked();
+                });
+            });
+        }
+
+        if dismiss_error {
+            self.load_error = None;
+        }
+
+        let mut close_bundle = false;
+        if let Some((path, descriptions)) = &self.opened_bundle {
+            egui::Window::new("Plugins in the bundle").show(ctx, |ui| {
+                ui.label(path);
+
+                for description in descriptions {
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        if ui.button("+").clicked() {
+                            if let Err(err) = self.plugins_container.load(path, &description.id) {
+                                self.load_error = Some(err);
+                            }
+                        }
+                        ui.vertical(|ui| {
+                            ui.strong(&description.name);
+                            ui.label(&description.id);
+                            ui.label(description.features.join(", "));
+                        });
+                    });
+                }
+
+                ui.separator();
+                close_bundle = ui.button("Close").clicked();
+            });
+        }
+
+        if close_bundle {
+            self.opened_bundle = None;
+        }
+
+        if self.plugins_container.uses_graph() {
+            egui::Window::new("Routing").show(ctx, |ui| self.routing_ui(ui));
+        }
+
         // it always MUST be the latest. See https://docs.rs/egui/latest/egui/containers/panel/struct.CentralPanel.html
         egui::CentralPanel::default().show(ctx, |ui| {
             if self.audio_io.is_activated() {
@@ -168,12 +1041,28 @@ impl eframe::App for TemplateApp {
                 ui.label("Deactivated");
             }
 
+            let mut use_graph = self.plugins_container.uses_graph();
+            if ui.checkbox(&mut use_graph, "Routing graph").changed() {
+                self.plugins_container.set_use_graph(use_graph);
+            }
+
             if ui.button("+").clicked() {
                 if let Some(path) = rfd::FileDialog::new()
                     .add_filter("CLAP bundle/plugin", &["clap"])
                     .pick_file()
                 {
-                    self.plugins_container.load(&path.display().to_string());
+                    let path = path.display().to_string();
+
+                    match describe_bundle(&path) {
+                        Ok(mut descriptions) if descriptions.len() == 1 => {
+                            let description = descriptions.remove(0);
+                            if let Err(err) = self.plugins_container.load(&path, &description.id) {
+                                self.load_error = Some(err);
+                            }
+                        }
+                        Ok(descriptions) => self.opened_bundle = Some((path, descriptions)),
+                        Err(err) => self.load_error = Some(err),
+                    }
                 }
             }
 
@@ -185,47 +1074,247 @@ impl eframe::App for TemplateApp {
                 };
                 ui.label(label);
 
+                let mut plugin_to_move = None;
+                let mut forward_notes = None;
+                let plugins_count = self.plugins_container.plugins.len();
+                let forwarding: Vec<_> = self
+                    .plugins_container
+                    .plugins
+                    .iter()
+                    .map(|plugin| plugin.id)
+                    .filter(|id| self.plugins_container.forwards_notes(*id))
+                    .collect();
+
+                // The routing window sets them in graph mode
+                let uses_graph = self.plugins_container.uses_graph();
+                let plugin_names: Vec<_> = self
+                    .plugins_container
+                    .plugins
+                    .iter()
+                    .map(|plugin| (plugin.id, plugin.name().to_owned()))
+                    .collect();
+                let chain_sidechains: Vec<_> = self
+                    .plugins_container
+                    .plugins
+                    .iter()
+                    .flat_map(|plugin| {
+                        (0..plugin.input_ports.len()).map(move |port| (plugin.id, port))
+                    })
+                    .map(|(id, port)| (id, port, self.plugins_container.chain_sidechain(id, port)))
+                    .collect();
+                let source_name = |source: Option<NodeKind>| match source {
+                    None => "None".to_owned(),
+                    Some(NodeKind::Plugin(plugin_id)) => plugin_names
+                        .iter()
+                        .find(|(id, _)| *id == plugin_id)
+                        .map_or_else(|| format!("Plugin #{plugin_id}"), |(_, name)| name.clone()),
+                    Some(_) => "Input".to_owned(),
+                };
+                let mut sidechain_to_set = None;
+
                 ui.horizontal(|ui| {
                     for (index, plugin) in self.plugins_container.plugins.iter_mut().enumerate() {
-                        ui.horizontal(|ui| {
-                            if ui.button("-").clicked() {
-                                self.plugins_to_remove.push(index);
-                            }
-                            ui.vertical(|ui| {
-                                ui.label(plugin.name());
-
-                                let mut changed_params = vec![];
-                                for param in &plugin.params {
-                                    ui.horizontal(|ui| {
-                                        ui.add(
-                                            Slider::from_get_set(
-                                                param.min_value..=param.max_value,
-                                                |value| {
-                                                    if let Some(value) = value {
-                                                        changed_params.push((param.id, value));
-                                                    }
+                        let (_, dropped) =
+                            ui.dnd_drop_zone::<usize, _>(egui::Frame::none(), |ui| {
+                                ui.horizontal(|ui| {
+                                    ui.vertical(|ui| {
+                                        ui.dnd_drag_source(
+                                            egui::Id::new(("plugin", plugin.id)),
+                                            index,
+                                            |ui| ui.label("☰"),
+                                        );
+                                        if ui.button("-").clicked() {
+                                            self.plugins_to_remove.push(index);
+                                        }
+                                    });
+                                    ui.vertical(|ui| {
+                                        ui.label(plugin.name());
+
+                                        ui.collapsing("Ports", |ui| {
+                                            for port in &plugin.input_ports {
+                                                ui.label(format!("In: {port}"));
+                                            }
+                                            for port in &plugin.output_ports {
+                                                ui.label(format!("Out: {port}"));
+                                            }
+                                            for port in &plugin.note_ports {
+                                                ui.label(format!("Notes in: {port}"));
+                                            }
+                                        });
 
-                                                    param.value
+                                        if !plugin.port_configs.is_empty() {
+                                            let selected = plugin.selected_port_config();
+                                            let selected_name = plugin
+                                                .port_configs
+                                                .iter()
+                                                .find(|config| Some(config.id) == selected)
+                                                .map_or("Default", |config| &config.name);
+                                            let mut selected_config = None;
+
+                                            egui::ComboBox::from_id_source((
+                                                "port_config",
+                                                plugin.id,
+                                            ))
+                                            .selected_text(selected_name)
+                                            .show_ui(
+                                                ui,
+                                                |ui| {
+                                                    for config in &plugin.port_configs {
+                                                        let label = format!(
+                                                            "{} ({} in, {} out)",
+                                                            config.name,
+                                                            config.input_port_count,
+                                                            config.output_port_count
+                                                        );
+                                                        if ui
+                                                            .selectable_label(
+                                                                Some(config.id) == selected,
+                                                                label,
+                                                            )
+                                                            .clicked()
+                                                        {
+                                                            selected_config = Some(config.id);
+                                                        }
+                                                    }
                                                 },
+                                            );
+
+                                            if let Some(config_id) = selected_config {
+                                                if let Err(err) =
+                                                    plugin.select_port_config(config_id)
+                                                {
+                                                    self.load_error = Some(err);
+                                                }
+                                            }
+                                        }
+
+                                        if plugin.supports_f64() {
+                                            let mut use_f64 = plugin.uses_f64();
+                                            if ui.checkbox(&mut use_f64, "64-bit").changed() {
+                                                plugin.set_use_f64(use_f64);
+                                            }
+                                        }
+
+                                        for (port_index, port) in
+                                            plugin.input_ports.iter().enumerate()
+                                        {
+                                            if uses_graph || port.is_main() {
+                                                continue;
+                                            }
+
+                                            let source = chain_sidechains
+                                                .iter()
+                                                .find(|(id, index, _)| {
+                                                    *id == plugin.id && *index == port_index
+                                                })
+                                                .and_then(|(_, _, source)| *source);
+                                            let mut options = vec![None, Some(NodeKind::Input)];
+                                            options.extend(
+                                                plugin_names
+                                                    .iter()
+                                                    .filter(|(id, _)| *id != plugin.id)
+                                                    .map(|(id, _)| Some(NodeKind::Plugin(*id))),
+                                            );
+
+                                            egui::ComboBox::new(
+                                                ("sidechain", plugin.id, port_index),
+                                                &port.name,
                                             )
-                                            .text(&param.name),
-                                        );
-                                    });
-                                }
+                                            .selected_text(source_name(source))
+                                            .show_ui(
+                                                ui,
+                                                |ui| {
+                                                    for option in options {
+                                                        if ui
+                                                            .selectable_label(
+                                                                source == option,
+                                                                source_name(option),
+                                                            )
+                                                            .clicked()
+                                                        {
+                                                            sidechain_to_set = Some((
+                                                                plugin.id, port_index, option,
+                                                            ));
+                                                        }
+                                                    }
+                                                },
+                                            );
+                                        }
 
-                                for (param_id, value) in changed_params {
-                                    plugin.set_value(param_id, value);
-                                }
-                            })
-                        });
+                                        if index + 1 < plugins_count {
+                                            let mut forwards = forwarding.contains(&plugin.id);
+                                            if ui
+                                                .checkbox(&mut forwards, "Notes to next plugin")
+                                                .changed()
+                                            {
+                                                forward_notes = Some((plugin.id, forwards));
+                                            }
+                                        }
+
+                                        let mut changed_params = vec![];
+                                        for param in &plugin.params {
+                                            ui.horizontal(|ui| {
+                                                // Don't fight the plugin's GUI
+                                                ui.add_enabled(
+                                                    !param.is_adjusted,
+                                                    Slider::from_get_set(
+                                                        param.min_value..=param.max_value,
+                                                        |value| {
+                                                            if let Some(value) = value {
+                                                                changed_params
+                                                                    .push((param.id, value));
+                                                            }
+
+                                                            param.value
+                                                        },
+                                                    )
+                                                    .text(&param.name),
+                                                );
+                                            });
+                                        }
+
+                                        for (param_id, value) in changed_params {
+                                            plugin.set_value(param_id, value, &mut self.audio_io);
+                                        }
+                                    })
+                                });
+                            });
+
+                        if let Some(from) = dropped {
+                            plugin_to_move = Some((*from, index));
+                        }
                     }
                 });
 
+                if let Some((from, to)) = plugin_to_move {
+                    self.plugins_container.move_plugin(from, to);
+                }
+
+                if let Some((plugin_id, forward)) = forward_notes {
+                    self.plugins_container.set_forward_notes(plugin_id, forward);
+                }
+
+                if let Some((plugin_id, port, source)) = sidechain_to_set {
+                    self.routing_error = self
+                        .plugins_container
+                        .set_chain_sidechain(plugin_id, port, source)
+                        .err();
+                }
+                if let (false, Some(err)) = (uses_graph, &self.routing_error) {
+                    ui.colored_label(ui.visuals().error_fg_color, err.to_string());
+                }
+
+                ui.separator();
+                self.keyboard_ui(ui);
+
+                ui.collapsing("MIDI file", |ui| self.midi_player_ui(ui));
+                ui.collapsing("Record plugin notes", |ui| self.recorder_ui(ui));
+
                 self.plugins_to_remove.sort();
                 self.plugins_to_remove.reverse();
 
                 for index in &self.plugins_to_remove {
-                    self.plugins_container.unload(*index);
+                    self.plugins_container.unload(*index, &mut self.audio_io);
                 }
 
                 self.plugins_to_remove = vec![];
@@ -234,6 +1323,52 @@ impl eframe::App for TemplateApp {
     }
 }
 
+/// Without a selected config, the device's default one is used.
+fn find_output_config(
+    device_name: &str,
+    config_name: &str,
+) -> Option<(Device, SupportedStreamConfig)> {
+    let device = cpal::default_host()
+        .devices()
+        .ok()?
+        .find(|device| device.name().map_or(false, |name| name == device_name))?;
+
+    if config_name.is_empty() {
+        let config = device.default_output_config().ok()?;
+        return Some((device, config));
+    }
+
+    let config = device
+        .supported_output_configs()
+        .ok()?
+        .find(|config| format_stream_config(config) == config_name)?;
+    let sample_rate =
+        PREFERRED_SAMPLE_RATE.clamp(config.min_sample_rate().0, config.max_sample_rate().0);
+
+    Some((device, config.with_sample_rate(SampleRate(sample_rate))))
+}
+
+/// Input configs are opened at the output rate whenever they support it.
+fn find_input_config(
+    device_name: &str,
+    config_name: &str,
+    sample_rate: f64,
+) -> Option<(Device, SupportedStreamConfig)> {
+    let device = cpal::default_host()
+        .devices()
+        .ok()?
+        .find(|device| device.name().map_or(false, |name| name == device_name))?;
+
+    let config = device
+        .supported_input_configs()
+        .ok()?
+        .find(|config| format_stream_config(config) == config_name)?;
+    let sample_rate =
+        (sample_rate as u32).clamp(config.min_sample_rate().0, config.max_sample_rate().0);
+
+    Some((device, config.with_sample_rate(SampleRate(sample_rate))))
+}
+
 fn format_stream_config(config: &SupportedStreamConfigRange) -> String {
     let sample_rate = if config.min_sample_rate() == config.max_sample_rate() {
         format!("{}", config.min_sample_rate().0)
@@ -245,5 +1380,16 @@ fn format_stream_config(config: &SupportedStreamConfigRange) -> String {
         )
     };
 
-    format!("{}, {sample_rate}", config.channels())
+    format!(
+        "{}, {sample_rate}, {}",
+        config.channels(),
+        config.sample_format()
+    )
+}
+
+fn format_processing_sample_rate(sample_rate: Option<u32>) -> String {
+    match sample_rate {
+        Some(sample_rate) => format!("{sample_rate} Hz"),
+        None => "Device rate".to_owned(),
+    }
 }
@@ -1,35 +1,230 @@
+use std::ops::RangeInclusive;
+
 use cpal::ChannelCount;
-use rtrb::{Consumer, Producer, RingBuffer};
+use rtrb::{Consumer, Producer};
+
+use crate::{
+    audio_input::InputBridge,
+    block_splitter::BlockSplitter,
+    execution_plan::ExecutionPlan,
+    midi_player::MidiPlayer,
+    notes::{CapturedMidi, TargetedNote},
+    plugin_processor::{ParamMessage, ParamOutput, PluginAudioProcessor, PluginId},
+    resampler::Resampler,
+    transport::Transport,
+};
+
+/// The audio thread never makes room for more.
+pub const MAX_PLUGINS: usize = 64;
+
+/// The queues between the main thread and the audio callback.
+pub struct AudioQueues {
+    pub notes: Consumer<TargetedNote>,
+    pub params: Consumer<ParamMessage>,
+    pub captured: Producer<CapturedMidi>,
+    pub param_outputs: Producer<ParamOutput>,
+}
 
 pub struct Audio {
-    rb_rx: Consumer<f32>,
+    channels: usize,
+    /// The processing rate.
+    sample_rate: f64,
+    splitter: BlockSplitter,
+    /// Converts from the processing rate to the device rate, if they differ.
+    resampler: Option<Resampler>,
+    plugins: Vec<Box<PluginAudioProcessor>>,
+    plan: Box<ExecutionPlan>,
+    input_buffers: Vec<Vec<f32>>,
 }
 
 impl Audio {
-    pub fn init(channels: ChannelCount) -> (Self, Producer<f32>) {
-        let (rb_tx, rb_rx) = RingBuffer::new(channels as usize * 44100);
-
-        (Self { rb_rx }, rb_tx)
-    }
-
-    // pub fn play(&mut self) {
-    //     if let Some(stream) = &self.output_stream {
-    //         match stream.play() {
-    //             Ok(_) => {
-    //                 println!("SUCCESSFULLY STARTED PLAYING");
-    //             }
-    //             Err(err) => {
-    //                 println!("PLAY ERROR: {:?}", err);
-    //             }
-    //         }
-    //     }
-    // }
-
-    pub fn process(&mut self, output: &mut [f32]) {
-        if let Ok(chunk) = self.rb_rx.read_chunk(output.len()) {
-            for (index, value) in chunk.into_iter().enumerate() {
-                output[index] = value;
+    pub fn init(
+        channels: ChannelCount,
+        frames_count_range: RangeInclusive<u32>,
+        sample_rate: u32,
+        processing_sample_rate: u32,
+    ) -> Self {
+        let min_frames = *frames_count_range.start() as usize;
+        let max_frames = *frames_count_range.end() as usize;
+
+        Self {
+            channels: channels as usize,
+            sample_rate: processing_sample_rate as f64,
+            splitter: BlockSplitter::new(channels as usize, min_frames, max_frames),
+            resampler: (sample_rate != processing_sample_rate)
+                .then(|| Resampler::new(channels as usize, processing_sample_rate, sample_rate)),
+            plugins: Vec::with_capacity(MAX_PLUGINS),
+            plan: Box::new(ExecutionPlan::empty(channels as usize, max_frames)),
+            input_buffers: vec![vec![0.0; max_frames]; channels as usize],
+        }
+    }
+
+    /// Starts the plugin's processing. It's handed back if there's no room
+    /// for it or it fails to start.
+    pub fn add_plugin(
+        &mut self,
+        mut plugin: Box<PluginAudioProcessor>,
+    ) -> Result<(), Box<PluginAudioProcessor>> {
+        // Never grow the vector on the audio thread
+        if self.plugins.len() == self.plugins.capacity() || !plugin.start_processing() {
+            return Err(plugin);
+        }
+
+        self.plugins.push(plugin);
+        Ok(())
+    }
+
+    pub fn remove_plugin(&mut self, id: PluginId) -> Option<Box<PluginAudioProcessor>> {
+        let index = self.plugins.iter().position(|plugin| plugin.id == id)?;
+        let mut plugin = self.plugins.remove(index);
+        plugin.stop_processing();
+        Some(plugin)
+    }
+
+    /// Has to be called before the audio is handed back to the main thread.
+    pub fn stop_plugins(&mut self) {
+        for plugin in &mut self.plugins {
+            plugin.stop_processing();
+        }
+    }
+
+    pub fn wake_plugin(&mut self, id: PluginId) {
+        if let Some(plugin) = self.plugins.iter_mut().find(|plugin| plugin.id == id) {
+            plugin.wake();
+        }
+    }
+
+    /// Returns the previous plan so it's deallocated off the audio thread.
+    pub fn set_plan(&mut self, plan: Box<ExecutionPlan>) -> Box<ExecutionPlan> {
+        std::mem::replace(&mut self.plan, plan)
+    }
+
+    pub fn into_plugins(self) -> Vec<Box<PluginAudioProcessor>> {
+        self.plugins
+    }
+
+    pub fn process(
+        &mut self,
+        output: &mut [f32],
+        mut input: Option<&mut InputBridge>,
+        mut player: Option<&mut MidiPlayer>,
+        transport: &mut Transport,
+        queues: &mut AudioQueues,
+    ) {
+        let Self {
+            channels,
+            sample_rate,
+            splitter,
+            resampler,
+            plugins,
+            plan,
+            input_buffers,
+        } = self;
+
+        let mut render = |block: &mut [f32]| {
+            let frames = block.len() / *channels;
+
+            // Left in the queue for the next block when the plugin has no room,
+            // a lost note off would hang
+            while let Ok(TargetedNote { plugin_id, note }) = queues.notes.peek() {
+                if let Some(plugin) = plugins.iter_mut().find(|plugin| plugin.id == *plugin_id) {
+                    if !plugin.push_note(0, *note) {
+                        break;
+                    }
+                }
+                let _ = queues.notes.pop();
+            }
+
+            // Left in the queue for the next block when the plugin has no room,
+            // the UI already shows the new value
+            while let Ok(message) = queues.params.peek() {
+                if let ParamMessage::Change(change) = message {
+                    if plugins
+                        .iter()
+                        .any(|plugin| plugin.id == change.plugin_id && plugin.is_input_full())
+                    {
+                        break;
+                    }
+                }
+
+                let Ok(message) = queues.params.pop() else {
+                    break;
+                };
+                match message {
+                    ParamMessage::Change(change) => {
+                        if let Some(plugin) = plugins
+                            .iter_mut()
+                            .find(|plugin| plugin.id == change.plugin_id)
+                        {
+                            plugin.push_param(change);
+                        }
+                    }
+                    ParamMessage::Clear {
+                        plugin_id,
+                        param_id,
+                    } => {
+                        if let Some(plugin) =
+                            plugins.iter_mut().find(|plugin| plugin.id == plugin_id)
+                        {
+                            plugin.clear_param(param_id);
+                        }
+                    }
+                }
             }
+
+            if let Some(player) = player.as_deref_mut() {
+                player.render(
+                    frames,
+                    *sample_rate,
+                    transport,
+                    |plugin_id, time, message| {
+                        plugins
+                            .iter_mut()
+                            .find(|plugin| plugin.id == plugin_id)
+                            .map_or(true, |plugin| plugin.push_midi(time, message))
+                    },
+                );
+            }
+
+            match input.as_deref_mut() {
+                Some(input) => input.read(input_buffers, frames),
+                None => {
+                    for buffer in input_buffers.iter_mut() {
+                        buffer[..frames].fill(0.0);
+                    }
+                }
+            }
+
+            plan.process(plugins, input_buffers, frames, &transport.event());
+
+            // Dropped when the main thread doesn't keep up. Captured MIDI is
+            // timed on the transport, which stands still while stopped.
+            let is_capturing = transport.is_playing();
+            for plugin in plugins.iter() {
+                for (time, message) in plugin.output_midi().iter().filter(|_| is_capturing) {
+                    let _ = queues.captured.push(CapturedMidi {
+                        plugin_id: plugin.id,
+                        beats: transport.beats_at(*time, *sample_rate),
+                        tempo: transport.tempo(),
+                        message: *message,
+                    });
+                }
+                for output in plugin.output_params() {
+                    let _ = queues.param_outputs.push(*output);
+                }
+            }
+            transport.advance(frames, *sample_rate);
+
+            for (frame, samples) in block.chunks_exact_mut(*channels).enumerate() {
+                for (channel, sample) in samples.iter_mut().enumerate() {
+                    *sample = plan.output(channel).map_or(0.0, |output| output[frame]);
+                }
+            }
+        };
+
+        match resampler {
+            Some(resampler) => resampler.fill(output, |chunk| splitter.fill(chunk, &mut render)),
+            None => splitter.fill(output, render),
         }
     }
 }
@@ -0,0 +1,293 @@
+use cpal::{Data, FromSample, Sample, SampleFormat, SizedSample};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::resampler::Resampler;
+
+const CAPACITY_FRAMES: usize = 8192;
+/// How much input is buffered before it's played, and what the drift
+/// correction steers towards.
+const TARGET_FRAMES: usize = 1024;
+const FILL_SMOOTHING: f64 = 0.995;
+
+/// Carries samples from the input stream callback to the output one, and
+/// converts them to the processing rate if it differs from the device rate.
+pub struct InputBridge {
+    ring: InputRing,
+    channels: usize,
+    resampler: Option<Resampler>,
+    scratch: Vec<f32>,
+}
+
+/// The two streams run on separate clocks, so the fill level slowly drifts;
+/// it's kept around `TARGET_FRAMES` by dropping or repeating a single frame
+/// at a time.
+struct InputRing {
+    rx: Consumer<f32>,
+    average_fill: f64,
+    is_primed: bool,
+    last_frame: Vec<f32>,
+}
+
+impl InputBridge {
+    pub fn new(
+        channels: usize,
+        sample_rate: u32,
+        processing_sample_rate: u32,
+    ) -> (Self, Producer<f32>) {
+        let (tx, rx) = RingBuffer::new(CAPACITY_FRAMES * channels);
+
+        (
+            Self {
+                ring: InputRing::new(rx, channels),
+                channels,
+                resampler: (sample_rate != processing_sample_rate)
+                    .then(|| Resampler::new(channels, sample_rate, processing_sample_rate)),
+                scratch: vec![0.0; CAPACITY_FRAMES * channels],
+            },
+            tx,
+        )
+    }
+
+    /// Fills the first `frames` samples of each of `buffers` with input.
+    pub fn read(&mut self, buffers: &mut [Vec<f32>], frames: usize) {
+        let Self {
+            ring,
+            channels,
+            resampler,
+            scratch,
+        } = self;
+        let scratch = &mut scratch[..frames * *channels];
+
+        match resampler {
+            Some(resampler) => resampler.fill(scratch, |chunk| ring.read(chunk, *channels)),
+            None => ring.read(scratch, *channels),
+        }
+
+        for buffer in buffers.iter_mut() {
+            buffer[..frames].fill(0.0);
+        }
+
+        for (frame, samples) in scratch.chunks_exact(*channels).enumerate() {
+            // Device input channels beyond the buffers are dropped
+            for (buffer, sample) in buffers.iter_mut().zip(samples) {
+                buffer[frame] = *sample;
+            }
+        }
+
+        // Mono input feeds every channel
+        if *channels == 1 {
+            let (first, rest) = buffers.split_at_mut(1);
+            for buffer in rest {
+                buffer[..frames].copy_from_slice(&first[0][..frames]);
+            }
+        }
+    }
+}
+
+impl InputRing {
+    fn new(rx: Consumer<f32>, channels: usize) -> Self {
+        Self {
+            rx,
+            average_fill: TARGET_FRAMES as f64,
+            is_primed: false,
+            last_frame: vec![0.0; channels],
+        }
+    }
+
+    /// Fills the interleaved `output` with input at the device rate.
+    fn read(&mut self, output: &mut [f32], channels: usize) {
+        output.fill(0.0);
+
+        let frames = output.len() / channels;
+        let mut available = self.rx.slots() / channels;
+        // Reads take a whole block, so that's the least that's kept
+        let target = TARGET_FRAMES.max(frames);
+
+        if !self.is_primed && available < target {
+            return;
+        }
+
+        // The input keeps coming while nothing reads it, before priming and
+        // during stalls. That backlog is dropped at once, the drift
+        // correction would take minutes to work it off.
+        if !self.is_primed || available > target * 2 {
+            if let Ok(chunk) = self.rx.read_chunk((available - target) * channels) {
+                chunk.commit_all();
+            }
+            available = target;
+            self.is_primed = true;
+            self.average_fill = target as f64;
+        }
+
+        self.average_fill =
+            self.average_fill * FILL_SMOOTHING + available as f64 * (1.0 - FILL_SMOOTHING);
+
+        let mut frames_to_read = frames;
+        let mut frames_to_skip = 0;
+
+        if self.average_fill > target as f64 * 1.5 && available > frames {
+            // The input clock runs faster
+            frames_to_skip = 1;
+            self.average_fill -= 1.0;
+        } else if self.average_fill < target as f64 * 0.5 && frames > 1 {
+            // The input clock runs slower, repeat the last frame
+            frames_to_read -= 1;
+            self.average_fill += 1.0;
+        }
+
+        if available < frames_to_read + frames_to_skip {
+            // Underrun, wait until the buffer fills up again
+            self.is_primed = false;
+            return;
+        }
+
+        if let Ok(chunk) = self.rx.read_chunk(frames_to_skip * channels) {
+            chunk.commit_all();
+        }
+
+        let offset = (frames - frames_to_read) * channels;
+        for frame in output[..offset].chunks_exact_mut(channels) {
+            frame.copy_from_slice(&self.last_frame);
+        }
+
+        let Ok(chunk) = self.rx.read_chunk(frames_to_read * channels) else {
+            return;
+        };
+
+        for (index, sample) in chunk.into_iter().enumerate() {
+            self.last_frame[index % channels] = sample;
+            output[offset + index] = sample;
+        }
+    }
+}
+
+/// The input stream side: converts to f32, writes whatever fits and drops
+/// the rest.
+pub fn push_input(tx: &mut Producer<f32>, data: &Data) {
+    match data.sample_format() {
+        SampleFormat::F32 => push_samples::<f32>(tx, data),
+        SampleFormat::F64 => push_samples::<f64>(tx, data),
+        SampleFormat::I8 => push_samples::<i8>(tx, data),
+        SampleFormat::I16 => push_samples::<i16>(tx, data),
+        SampleFormat::I32 => push_samples::<i32>(tx, data),
+        SampleFormat::I64 => push_samples::<i64>(tx, data),
+        SampleFormat::U8 => push_samples::<u8>(tx, data),
+        SampleFormat::U16 => push_samples::<u16>(tx, data),
+        SampleFormat::U32 => push_samples::<u32>(tx, data),
+        SampleFormat::U64 => push_samples::<u64>(tx, data),
+        _ => {}
+    }
+}
+
+fn push_samples<T>(tx: &mut Producer<f32>, data: &Data)
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let Some(data) = data.as_slice::<T>() else {
+        return;
+    };
+    let samples = data.len().min(tx.slots());
+
+    if let Ok(chunk) = tx.write_chunk_uninit(samples) {
+        chunk.fill_from_iter(data.iter().map(|sample| sample.to_sample::<f32>()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(channels: usize) -> (InputRing, Producer<f32>) {
+        let (tx, rx) = RingBuffer::new(CAPACITY_FRAMES * channels);
+        (InputRing::new(rx, channels), tx)
+    }
+
+    /// Pushes frames whose samples are their index, on every channel.
+    fn push_frames(tx: &mut Producer<f32>, channels: usize, frames: std::ops::Range<usize>) {
+        for frame in frames {
+            for _ in 0..channels {
+                tx.push(frame as f32).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn waits_for_the_target_fill() {
+        let (mut ring, mut tx) = ring(2);
+        let mut output = vec![1.0; 2 * 256];
+
+        push_frames(&mut tx, 2, 0..TARGET_FRAMES - 1);
+        ring.read(&mut output, 2);
+
+        assert!(output.iter().all(|sample| *sample == 0.0));
+        assert_eq!(ring.rx.slots(), 2 * (TARGET_FRAMES - 1));
+    }
+
+    #[test]
+    fn drops_the_backlog_when_priming() {
+        let (mut ring, mut tx) = ring(2);
+        let mut output = vec![0.0; 2 * 256];
+
+        push_frames(&mut tx, 2, 0..CAPACITY_FRAMES);
+        ring.read(&mut output, 2);
+
+        let first = (CAPACITY_FRAMES - TARGET_FRAMES) as f32;
+        assert_eq!(output[0], first);
+        assert_eq!(output[2 * 255 + 1], first + 255.0);
+        assert_eq!(ring.rx.slots(), 2 * (TARGET_FRAMES - 256));
+    }
+
+    #[test]
+    fn drops_the_backlog_after_a_stall() {
+        let (mut ring, mut tx) = ring(1);
+        let mut output = vec![0.0; 256];
+
+        push_frames(&mut tx, 1, 0..TARGET_FRAMES);
+        ring.read(&mut output, 1);
+        assert_eq!(output[0], 0.0);
+        assert_eq!(output[255], 255.0);
+
+        // Nothing reads while the input keeps coming
+        push_frames(&mut tx, 1, TARGET_FRAMES..6000);
+        ring.read(&mut output, 1);
+
+        assert_eq!(output[0], (6000 - TARGET_FRAMES) as f32);
+        assert_eq!(ring.rx.slots(), TARGET_FRAMES - 256);
+    }
+
+    #[test]
+    fn reads_continuously_at_the_target_fill() {
+        let (mut ring, mut tx) = ring(2);
+        let mut output = vec![0.0; 2 * 128];
+
+        push_frames(&mut tx, 2, 0..TARGET_FRAMES);
+        let mut next_pushed = TARGET_FRAMES;
+        let mut next_read = 0;
+
+        for _ in 0..1000 {
+            ring.read(&mut output, 2);
+            for frame in output.chunks_exact(2) {
+                assert_eq!(frame, [next_read as f32; 2]);
+                next_read += 1;
+            }
+
+            push_frames(&mut tx, 2, next_pushed..next_pushed + 128);
+            next_pushed += 128;
+        }
+
+        assert_eq!(ring.rx.slots(), 2 * TARGET_FRAMES);
+    }
+
+    #[test]
+    fn primes_with_blocks_larger_than_the_target() {
+        let (mut ring, mut tx) = ring(1);
+        let mut output = vec![0.0; 2048];
+
+        push_frames(&mut tx, 1, 0..5000);
+        ring.read(&mut output, 1);
+
+        assert_eq!(output[0], (5000 - 2048) as f32);
+        assert_eq!(ring.rx.slots(), 0);
+    }
+}
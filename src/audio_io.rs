@@ -1,54 +1,228 @@
+use std::{
+    collections::VecDeque,
+    ops::RangeInclusive,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clack_host::prelude::PluginAudioConfiguration;
 use cpal::{
-    traits::{DeviceTrait, HostTrait},
-    Stream, StreamConfig,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    BufferSize, Data, Device, Stream, StreamConfig, SupportedBufferSize, SupportedStreamConfig,
 };
-use rtrb::{Producer, RingBuffer};
+use rtrb::{Consumer, Producer, PushError, RingBuffer};
 
-use crate::audio::Audio;
+use crate::{
+    audio::{Audio, AudioQueues},
+    audio_input::{push_input, InputBridge},
+    execution_plan::ExecutionPlan,
+    midi_player::{MidiPlayer, PlayerSettings},
+    notes::{CapturedMidi, Note, TargetedNote, CAPTURE_QUEUE_CAPACITY, NOTE_QUEUE_CAPACITY},
+    plugin_processor::{
+        ParamChange, ParamMessage, ParamOutput, PluginAudioProcessor, PluginId,
+        PARAM_QUEUE_CAPACITY,
+    },
+    sample_writer::SampleWriter,
+    transport::{Transport, TransportCommand, TransportPosition, TransportSettings},
+};
 
 enum AudioIOMsg {
     NewAudio(Audio),
     NoAudio,
+    AddPlugin(Box<PluginAudioProcessor>),
+    RemovePlugin(PluginId),
+    WakePlugin(PluginId),
+    SetPlan(Box<ExecutionPlan>),
+    SetInput(Option<Box<InputBridge>>),
+    SetPlayer(Option<Box<MidiPlayer>>),
+    SetPlayerSettings(PlayerSettings),
+    Transport(TransportCommand),
+}
+
+enum AudioIOFeedback {
+    OldAudio(Audio),
+    PluginRemoved(Box<PluginAudioProcessor>),
+    /// There was no room for the plugin or it failed to start processing.
+    PluginRejected(Box<PluginAudioProcessor>),
+    OldPlan(Box<ExecutionPlan>),
+    OldInput(Box<InputBridge>),
+    OldPlayer(Box<MidiPlayer>),
+}
+
+/// A replaced output stream whose callback still holds audio. It keeps
+/// running until the audio is handed back, so the processors in it aren't
+/// dropped with it.
+struct ClosingStream {
+    _output_stream: Stream,
+    io_rx: Consumer<AudioIOFeedback>,
+    sent_audios: usize,
 }
 
+/// Each message makes at most one feedback, the callback only takes a
+/// message when there's room for its feedback.
+const MSG_QUEUE_CAPACITY: usize = 32;
+const FEEDBACK_QUEUE_CAPACITY: usize = 32;
+const CLOSE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Used when the device doesn't tell its buffer size range.
+const DEFAULT_MAX_FRAMES: u32 = 1024;
+/// Devices may report huge maximums, plugins don't need blocks beyond that.
+const MAX_FRAMES_LIMIT: u32 = 8192;
+
 pub struct AudioIO {
     output_stream: Stream,
+    input_stream: Option<Stream>,
     io_tx: Producer<AudioIOMsg>,
+    /// Messages the callback had no room for yet, sent before any new one.
+    unsent_msgs: VecDeque<AudioIOMsg>,
+    io_rx: Consumer<AudioIOFeedback>,
+    note_tx: Producer<TargetedNote>,
+    param_tx: Producer<ParamMessage>,
+    captured_rx: Consumer<CapturedMidi>,
+    param_output_rx: Consumer<ParamOutput>,
+    transport_position: Arc<TransportPosition>,
+    transport_settings: TransportSettings,
     output_stream_config: StreamConfig,
-    audio_tx: Option<Producer<f32>>,
+    is_activated: bool,
+    frames_count_range: RangeInclusive<u32>,
+    /// The rate plugins run at, `None` to follow the device.
+    processing_sample_rate: Option<u32>,
+    returned_plugins: Vec<Box<PluginAudioProcessor>>,
+    rejected_plugins: Vec<Box<PluginAudioProcessor>>,
+    /// Audios sent to the callback and not handed back yet.
+    sent_audios: usize,
+    closing_streams: Vec<ClosingStream>,
 }
 
 impl AudioIO {
     pub fn init() -> Self {
         let host = cpal::default_host();
         let output_device = host.default_output_device().unwrap();
-        let output_stream_config = output_device
+        let output_config = output_device
             .supported_output_configs()
             .unwrap()
             .next()
             .unwrap()
-            .with_max_sample_rate()
-            .into();
+            .with_max_sample_rate();
 
-        let (io_tx, mut io_rx) = RingBuffer::new(32);
+        Self::open(&output_device, output_config).unwrap()
+    }
+
+    pub fn open(
+        output_device: &Device,
+        output_config: SupportedStreamConfig,
+    ) -> Result<Self, String> {
+        let output_stream_config = output_config.config();
+        let frames_count_range = frames_count_range(&output_stream_config, &output_config);
+        let mut writer = SampleWriter::new(
+            *frames_count_range.end() as usize * output_stream_config.channels as usize,
+        );
+
+        let (io_tx, mut callback_rx) = RingBuffer::new(MSG_QUEUE_CAPACITY);
+        let (mut callback_tx, io_rx) = RingBuffer::new(FEEDBACK_QUEUE_CAPACITY);
+        let (note_tx, note_rx) = RingBuffer::new(NOTE_QUEUE_CAPACITY);
+        let (param_tx, param_rx) = RingBuffer::new(PARAM_QUEUE_CAPACITY);
+        let (captured_tx, captured_rx) = RingBuffer::new(CAPTURE_QUEUE_CAPACITY);
+        let (param_output_tx, param_output_rx) = RingBuffer::new(PARAM_QUEUE_CAPACITY);
+        let mut queues = AudioQueues {
+            notes: note_rx,
+            params: param_rx,
+            captured: captured_tx,
+            param_outputs: param_output_tx,
+        };
 
-        let stream = output_device.build_output_stream(
+        let mut audio: Option<Audio> = None;
+        let mut input: Option<Box<InputBridge>> = None;
+        let mut player: Option<Box<MidiPlayer>> = None;
+        let transport_position = Arc::new(TransportPosition::default());
+        let mut transport = Transport::new(transport_position.clone());
+
+        let stream = output_device.build_output_stream_raw(
             &output_stream_config,
-            move |output: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                println!("OUTPUT DATA LENGTH: {}", output.len());
+            output_config.sample_format(),
+            move |data: &mut Data, _: &cpal::OutputCallbackInfo| {
+                // The feedback pushes below can't fail, so nothing is dropped
+                // on the audio thread
+                while callback_tx.slots() > 0 {
+                    let Ok(msg) = callback_rx.pop() else {
+                        break;
+                    };
 
-                let mut audio = None;
+                    match msg {
+                        AudioIOMsg::NewAudio(new_audio) => {
+                            if let Some(mut old_audio) = audio.replace(new_audio) {
+                                old_audio.stop_plugins();
+                                let _ = callback_tx.push(AudioIOFeedback::OldAudio(old_audio));
+                            }
+                        }
+                        AudioIOMsg::NoAudio => {
+                            if let Some(mut old_audio) = audio.take() {
+                                old_audio.stop_plugins();
+                                let _ = callback_tx.push(AudioIOFeedback::OldAudio(old_audio));
+                            }
+                        }
+                        AudioIOMsg::AddPlugin(plugin) => {
+                            let feedback = match &mut audio {
+                                Some(audio) => audio
+                                    .add_plugin(plugin)
+                                    .err()
+                                    .map(AudioIOFeedback::PluginRejected),
+                                // Never started, it's sent again with the next audio
+                                None => Some(AudioIOFeedback::PluginRemoved(plugin)),
+                            };
 
-                while let Ok(msg) = io_rx.pop() {
-                    audio = match msg {
-                        AudioIOMsg::NewAudio(new_audio) => Some(new_audio),
-                        AudioIOMsg::NoAudio => None,
+                            if let Some(feedback) = feedback {
+                                let _ = callback_tx.push(feedback);
+                            }
+                        }
+                        AudioIOMsg::RemovePlugin(id) => {
+                            if let Some(plugin) =
+                                audio.as_mut().and_then(|audio| audio.remove_plugin(id))
+                            {
+                                let _ = callback_tx.push(AudioIOFeedback::PluginRemoved(plugin));
+                            }
+                        }
+                        AudioIOMsg::WakePlugin(id) => {
+                            if let Some(audio) = &mut audio {
+                                audio.wake_plugin(id);
+                            }
+                        }
+                        AudioIOMsg::SetPlan(plan) => {
+                            let old_plan = match &mut audio {
+                                Some(audio) => audio.set_plan(plan),
+                                None => plan,
+                            };
+                            let _ = callback_tx.push(AudioIOFeedback::OldPlan(old_plan));
+                        }
+                        AudioIOMsg::SetInput(new_input) => {
+                            if let Some(old_input) = std::mem::replace(&mut input, new_input) {
+                                let _ = callback_tx.push(AudioIOFeedback::OldInput(old_input));
+                            }
+                        }
+                        AudioIOMsg::SetPlayer(new_player) => {
+                            if let Some(old_player) = std::mem::replace(&mut player, new_player) {
+                                let _ = callback_tx.push(AudioIOFeedback::OldPlayer(old_player));
+                            }
+                        }
+                        AudioIOMsg::SetPlayerSettings(settings) => {
+                            if let Some(player) = &mut player {
+                                player.set_settings(settings);
+                            }
+                        }
+                        AudioIOMsg::Transport(command) => transport.handle(command),
                     }
                 }
 
-                if let Some(audio) = &mut audio {
-                    audio.process(output);
-                }
+                writer.write(data, |output| match &mut audio {
+                    Some(audio) => audio.process(
+                        output,
+                        input.as_deref_mut(),
+                        player.as_deref_mut(),
+                        &mut transport,
+                        &mut queues,
+                    ),
+                    None => output.fill(0.0),
+                });
             },
             move |err| {
                 println!("STREAM ERROR: {:?}", err);
@@ -56,35 +230,376 @@ impl AudioIO {
             None,
         );
 
-        let output_stream = match stream {
-            Ok(stream) => Some(stream),
-            Err(err) => {
-                println!("BUILD STREAM ERROR: {:?}", err);
-                None
-            }
-        }
-        .unwrap();
+        let output_stream =
+            stream.map_err(|err| format!("Failed to build the output stream: {err}"))?;
 
-        Self {
+        output_stream
+            .play()
+            .map_err(|err| format!("Failed to start the output stream: {err}"))?;
+
+        Ok(Self {
             output_stream,
+            input_stream: None,
             output_stream_config,
             io_tx,
-            audio_tx: None,
+            unsent_msgs: VecDeque::new(),
+            io_rx,
+            note_tx,
+            param_tx,
+            captured_rx,
+            param_output_rx,
+            transport_position,
+            transport_settings: TransportSettings::default(),
+            is_activated: false,
+            frames_count_range,
+            processing_sample_rate: None,
+            returned_plugins: vec![],
+            rejected_plugins: vec![],
+            sent_audios: 0,
+            closing_streams: vec![],
+        })
+    }
+
+    /// Replaces the stream with one on the given device and config. The
+    /// processors are taken back from the old stream first and the audio is
+    /// re-activated if it was active. The old stream is kept if the new one
+    /// fails to open.
+    pub fn reopen(
+        &mut self,
+        output_device: &Device,
+        output_config: SupportedStreamConfig,
+    ) -> Result<(), String> {
+        let was_activated = self.is_activated;
+        self.close();
+
+        let mut audio_io = match Self::open(output_device, output_config) {
+            Ok(audio_io) => audio_io,
+            Err(err) => {
+                if was_activated {
+                    self.activate();
+                }
+                return Err(err);
+            }
+        };
+        audio_io.processing_sample_rate = self.processing_sample_rate;
+        audio_io.set_transport_settings(self.transport_settings);
+
+        let mut old_audio_io = std::mem::replace(self, audio_io);
+        // Never reached the old callback
+        for msg in old_audio_io.unsent_msgs.drain(..) {
+            match msg {
+                AudioIOMsg::NewAudio(_) => old_audio_io.sent_audios -= 1,
+                AudioIOMsg::AddPlugin(plugin) => old_audio_io.returned_plugins.push(plugin),
+                _ => {}
+            }
+        }
+        self.returned_plugins = old_audio_io.returned_plugins;
+        self.rejected_plugins = old_audio_io.rejected_plugins;
+        self.closing_streams = old_audio_io.closing_streams;
+        if old_audio_io.sent_audios > 0 {
+            self.closing_streams.push(ClosingStream {
+                _output_stream: old_audio_io.output_stream,
+                io_rx: old_audio_io.io_rx,
+                sent_audios: old_audio_io.sent_audios,
+            });
+        }
+
+        if was_activated {
+            self.activate();
+        }
+
+        Ok(())
+    }
+
+    /// Runs the plugins at the given rate and resamples to and from the
+    /// device, or at the device rate with `None`. Active audio is replaced,
+    /// handing its processors back.
+    pub fn set_processing_sample_rate(&mut self, sample_rate: Option<u32>) {
+        if self.processing_sample_rate == sample_rate {
+            return;
+        }
+
+        self.processing_sample_rate = sample_rate;
+
+        if self.is_activated {
+            self.activate();
+        }
+    }
+
+    /// Starts feeding the input of the routing from the given device. The
+    /// input has to run at the output sample rate.
+    pub fn open_input(
+        &mut self,
+        input_device: &Device,
+        input_config: SupportedStreamConfig,
+    ) -> Result<(), String> {
+        let input_stream_config = input_config.config();
+
+        if input_stream_config.sample_rate != self.output_stream_config.sample_rate {
+            return Err(format!(
+                "The input runs at {} Hz, the output at {} Hz",
+                input_stream_config.sample_rate.0, self.output_stream_config.sample_rate.0
+            ));
+        }
+
+        let (bridge, mut input_tx) = InputBridge::new(
+            input_stream_config.channels as usize,
+            input_stream_config.sample_rate.0,
+            self.processing_sample_rate(),
+        );
+
+        let input_stream = input_device
+            .build_input_stream_raw(
+                &input_stream_config,
+                input_config.sample_format(),
+                move |data: &Data, _: &cpal::InputCallbackInfo| push_input(&mut input_tx, data),
+                move |err| {
+                    println!("INPUT STREAM ERROR: {:?}", err);
+                },
+                None,
+            )
+            .map_err(|err| format!("Failed to build the input stream: {err}"))?;
+
+        input_stream
+            .play()
+            .map_err(|err| format!("Failed to start the input stream: {err}"))?;
+
+        self.send(AudioIOMsg::SetInput(Some(Box::new(bridge))));
+        self.input_stream = Some(input_stream);
+
+        Ok(())
+    }
+
+    pub fn close_input(&mut self) {
+        if self.input_stream.take().is_some() {
+            self.send(AudioIOMsg::SetInput(None));
+        }
+    }
+
+    /// Deactivates the audio and waits for the callback to hand back every
+    /// audio sent to it, not only the active one.
+    fn close(&mut self) {
+        if self.is_activated {
+            self.deactivate();
+        }
+
+        let started = Instant::now();
+        loop {
+            self.drain_feedback();
+            if self.sent_audios == 0 || started.elapsed() >= CLOSE_TIMEOUT {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
         }
     }
 
     pub fn deactivate(&mut self) {
-        self.audio_tx = None;
-        self.io_tx.push(AudioIOMsg::NoAudio).unwrap();
+        self.is_activated = false;
+        self.send(AudioIOMsg::NoAudio);
     }
 
     pub fn activate(&mut self) {
-        let (audio, audio_tx) = Audio::init(self.output_stream_config.channels);
-        self.audio_tx = Some(audio_tx);
-        self.io_tx.push(AudioIOMsg::NewAudio(audio)).unwrap();
+        let audio = Audio::init(
+            self.output_stream_config.channels,
+            self.frames_count_range.clone(),
+            self.output_stream_config.sample_rate.0,
+            self.processing_sample_rate(),
+        );
+        self.is_activated = true;
+        self.send(AudioIOMsg::NewAudio(audio));
+        self.sent_audios += 1;
     }
 
     pub fn is_activated(&self) -> bool {
-        self.audio_tx.is_some()
+        self.is_activated
+    }
+
+    pub fn add_plugin(&mut self, plugin: Box<PluginAudioProcessor>) {
+        self.send(AudioIOMsg::AddPlugin(plugin));
+    }
+
+    pub fn remove_plugin(&mut self, id: PluginId) {
+        self.send(AudioIOMsg::RemovePlugin(id));
+    }
+
+    pub fn wake_plugin(&mut self, id: PluginId) {
+        self.send(AudioIOMsg::WakePlugin(id));
+    }
+
+    /// Sends a note to a plugin on the audio thread. Dropped if the queue
+    /// is full or the plugin isn't processing.
+    pub fn send_note(&mut self, plugin_id: PluginId, note: Note) {
+        let _ = self.note_tx.push(TargetedNote { plugin_id, note });
+    }
+
+    /// Sends a param change to a plugin on the audio thread, for its next
+    /// process call. Returns `false` if the queue is full.
+    pub fn send_param(&mut self, change: ParamChange) -> bool {
+        self.param_tx.push(ParamMessage::Change(change)).is_ok()
+    }
+
+    /// Drops the changes to a param that are still on their way to the
+    /// plugin. Ignored if the queue is full.
+    pub fn clear_param(&mut self, plugin_id: PluginId, param_id: u32) {
+        let _ = self.param_tx.push(ParamMessage::Clear {
+            plugin_id,
+            param_id,
+        });
+    }
+
+    /// Takes what the plugins played since the last call.
+    pub fn poll_captured_midi(&mut self) -> Vec<CapturedMidi> {
+        let mut captured = vec![];
+        while let Ok(event) = self.captured_rx.pop() {
+            captured.push(event);
+        }
+        captured
+    }
+
+    /// Takes the param changes plugins reported while processing.
+    pub fn poll_param_outputs(&mut self) -> Vec<ParamOutput> {
+        let mut outputs = vec![];
+        while let Ok(output) = self.param_output_rx.pop() {
+            outputs.push(output);
+        }
+        outputs
+    }
+
+    /// Replaces the MIDI file player. It lives with the stream, so it has to
+    /// be set again after `reopen`.
+    pub fn set_midi_player(&mut self, player: Option<MidiPlayer>) {
+        self.send(AudioIOMsg::SetPlayer(player.map(Box::new)));
+    }
+
+    pub fn set_player_settings(&mut self, settings: PlayerSettings) {
+        self.send(AudioIOMsg::SetPlayerSettings(settings));
+    }
+
+    pub fn send_transport_command(&mut self, command: TransportCommand) {
+        self.send(AudioIOMsg::Transport(command));
+    }
+
+    /// Kept across `reopen`, unlike the position.
+    pub fn set_transport_settings(&mut self, settings: TransportSettings) {
+        self.transport_settings = settings;
+        self.send_transport_command(TransportCommand::SetSettings(settings));
+    }
+
+    pub fn transport_position(&self) -> &TransportPosition {
+        &self.transport_position
+    }
+
+    pub fn set_plan(&mut self, plan: ExecutionPlan) {
+        self.send(AudioIOMsg::SetPlan(Box::new(plan)));
+    }
+
+    pub fn channels(&self) -> usize {
+        self.output_stream_config.channels as usize
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.output_stream_config.sample_rate.0 as f64
+    }
+
+    fn processing_sample_rate(&self) -> u32 {
+        self.processing_sample_rate
+            .unwrap_or(self.output_stream_config.sample_rate.0)
+    }
+
+    /// What the plugins have to be activated with to run in this stream.
+    pub fn plugin_audio_configuration(&self) -> PluginAudioConfiguration {
+        PluginAudioConfiguration {
+            sample_rate: self.processing_sample_rate() as f64,
+            frames_count_range: self.frames_count_range.clone(),
+        }
+    }
+
+    /// Collects the plugin processors the audio thread has handed back,
+    /// either on request or because the audio was deactivated.
+    pub fn poll_removed_plugins(&mut self) -> Vec<Box<PluginAudioProcessor>> {
+        self.drain_feedback();
+        std::mem::take(&mut self.returned_plugins)
+    }
+
+    /// Collects the plugin processors the audio thread couldn't start. They
+    /// shouldn't be sent again as is.
+    pub fn poll_rejected_plugins(&mut self) -> Vec<Box<PluginAudioProcessor>> {
+        self.drain_feedback();
+        std::mem::take(&mut self.rejected_plugins)
+    }
+
+    /// Messages are kept in order when the callback falls behind, and sent
+    /// once it catches up.
+    fn send(&mut self, msg: AudioIOMsg) {
+        self.unsent_msgs.push_back(msg);
+        self.send_pending();
+    }
+
+    fn send_pending(&mut self) {
+        while let Some(msg) = self.unsent_msgs.pop_front() {
+            if let Err(PushError::Full(msg)) = self.io_tx.push(msg) {
+                self.unsent_msgs.push_front(msg);
+                return;
+            }
+        }
+    }
+
+    /// Also sends what the callback had no room for before.
+    fn drain_feedback(&mut self) {
+        self.sent_audios -= drain_feedback(
+            &mut self.io_rx,
+            &mut self.returned_plugins,
+            &mut self.rejected_plugins,
+        );
+
+        let returned_plugins = &mut self.returned_plugins;
+        let rejected_plugins = &mut self.rejected_plugins;
+        self.closing_streams.retain_mut(|stream| {
+            stream.sent_audios -=
+                drain_feedback(&mut stream.io_rx, returned_plugins, rejected_plugins);
+            stream.sent_audios > 0
+        });
+    }
+}
+
+/// Sorts out what the callback handed back. Returns how many audios came
+/// back.
+fn drain_feedback(
+    io_rx: &mut Consumer<AudioIOFeedback>,
+    returned_plugins: &mut Vec<Box<PluginAudioProcessor>>,
+    rejected_plugins: &mut Vec<Box<PluginAudioProcessor>>,
+) -> usize {
+    let mut returned_audios = 0;
+
+    while let Ok(feedback) = io_rx.pop() {
+        match feedback {
+            AudioIOFeedback::OldAudio(audio) => {
+                returned_plugins.extend(audio.into_plugins());
+                returned_audios += 1;
+            }
+            AudioIOFeedback::PluginRemoved(plugin) => returned_plugins.push(plugin),
+            AudioIOFeedback::PluginRejected(plugin) => rejected_plugins.push(plugin),
+            AudioIOFeedback::OldPlan(_)
+            | AudioIOFeedback::OldInput(_)
+            | AudioIOFeedback::OldPlayer(_) => {}
+        }
+    }
+
+    returned_audios
+}
+
+/// A fixed buffer size is what every callback asks for, so plugins can be
+/// promised exactly that. Otherwise callbacks vary and the block splitter can
+/// cut them down to any size up to the device maximum.
+fn frames_count_range(
+    stream_config: &StreamConfig,
+    supported_config: &SupportedStreamConfig,
+) -> RangeInclusive<u32> {
+    match (&stream_config.buffer_size, supported_config.buffer_size()) {
+        (BufferSize::Fixed(frames), _) => *frames..=*frames,
+        (BufferSize::Default, SupportedBufferSize::Range { max, .. }) => {
+            1..=(*max).clamp(1, MAX_FRAMES_LIMIT)
+        }
+        (BufferSize::Default, SupportedBufferSize::Unknown) => 1..=DEFAULT_MAX_FRAMES,
     }
 }
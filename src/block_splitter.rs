@@ -0,0 +1,138 @@
+/// Adapts whatever number of frames cpal asks for to blocks within the
+/// `min_frames..=max_frames` range promised to the plugins. Frames rendered
+/// beyond what a callback needs are kept for the next one.
+pub struct BlockSplitter {
+    min_frames: usize,
+    max_frames: usize,
+    channels: usize,
+    block: Vec<f32>,
+    leftover: Vec<f32>,
+    leftover_start: usize,
+}
+
+impl BlockSplitter {
+    pub fn new(channels: usize, min_frames: usize, max_frames: usize) -> Self {
+        let min_frames = min_frames.clamp(1, max_frames);
+
+        Self {
+            min_frames,
+            max_frames,
+            channels,
+            block: vec![0.0; max_frames * channels],
+            leftover: Vec::with_capacity(max_frames * channels),
+            leftover_start: 0,
+        }
+    }
+
+    /// Fills the interleaved `output`, calling `render` with an interleaved
+    /// block to fill as many times as needed.
+    pub fn fill(&mut self, output: &mut [f32], mut render: impl FnMut(&mut [f32])) {
+        let leftover = &self.leftover[self.leftover_start..];
+        let mut written = leftover.len().min(output.len());
+        output[..written].copy_from_slice(&leftover[..written]);
+        self.leftover_start += written;
+
+        if self.leftover_start == self.leftover.len() {
+            self.leftover.clear();
+            self.leftover_start = 0;
+        }
+
+        while written < output.len() {
+            let remaining_frames = (output.len() - written) / self.channels;
+            let frames = remaining_frames.clamp(self.min_frames, self.max_frames);
+            let block = &mut self.block[..frames * self.channels];
+            render(block);
+
+            let used = block.len().min(output.len() - written);
+            output[written..written + used].copy_from_slice(&block[..used]);
+            // Capacity is reserved up front, so this doesn't allocate
+            self.leftover.extend_from_slice(&block[used..]);
+            written += used;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNELS: usize = 2;
+
+    /// Fills one callback of `frames` frames, rendering the frame numbers so
+    /// the output shows whether anything was lost or repeated. Returns the
+    /// size of every rendered block.
+    fn callback(splitter: &mut BlockSplitter, next_frame: &mut f32, frames: usize) -> Vec<f32> {
+        let mut output = vec![-1.0; frames * CHANNELS];
+        splitter.fill(&mut output, |block| {
+            for frame in block.chunks_exact_mut(CHANNELS) {
+                frame.fill(*next_frame);
+                *next_frame += 1.0;
+            }
+        });
+        output
+    }
+
+    fn block_sizes(splitter: &mut BlockSplitter, frames: usize) -> Vec<usize> {
+        let mut sizes = vec![];
+        let mut output = vec![0.0; frames * CHANNELS];
+        splitter.fill(&mut output, |block| sizes.push(block.len() / CHANNELS));
+        sizes
+    }
+
+    fn assert_continues(output: &[f32], first_frame: usize) {
+        for (index, frame) in output.chunks_exact(CHANNELS).enumerate() {
+            assert_eq!(frame, [(first_frame + index) as f32; CHANNELS]);
+        }
+    }
+
+    #[test]
+    fn carries_leftover_over_callbacks() {
+        let mut splitter = BlockSplitter::new(CHANNELS, 64, 256);
+        let mut next_frame = 0.0;
+
+        for callback_index in 0..20 {
+            let output = callback(&mut splitter, &mut next_frame, 10);
+            assert_continues(&output, callback_index * 10);
+        }
+        // Blocks of 64 frames rendered as the leftover ran out
+        assert_eq!(next_frame, 256.0);
+    }
+
+    #[test]
+    fn renders_at_least_min_frames() {
+        let mut splitter = BlockSplitter::new(CHANNELS, 32, 64);
+
+        assert_eq!(block_sizes(&mut splitter, 40), [40]);
+        // 6 frames are left to render after a full block
+        assert_eq!(block_sizes(&mut splitter, 70), [64, 32]);
+        // The 26 extra frames cover the next callback
+        assert!(block_sizes(&mut splitter, 26).is_empty());
+        assert_eq!(block_sizes(&mut splitter, 5), [32]);
+    }
+
+    #[test]
+    fn splits_callbacks_larger_than_max_frames() {
+        let mut splitter = BlockSplitter::new(CHANNELS, 1, 16);
+        assert_eq!(block_sizes(&mut splitter, 50), [16, 16, 16, 2]);
+
+        let mut splitter = BlockSplitter::new(CHANNELS, 1, 16);
+        let mut next_frame = 0.0;
+        let output = callback(&mut splitter, &mut next_frame, 50);
+        assert_continues(&output, 0);
+    }
+
+    #[test]
+    fn keeps_the_output_continuous_for_any_callback_size() {
+        let mut splitter = BlockSplitter::new(CHANNELS, 48, 128);
+        let capacity = splitter.leftover.capacity();
+        let mut next_frame = 0.0;
+        let mut written = 0;
+
+        for frames in [1, 47, 48, 49, 128, 129, 300, 3, 0, 64, 17, 200] {
+            let output = callback(&mut splitter, &mut next_frame, frames);
+            assert_continues(&output, written);
+            written += frames;
+            assert_eq!(splitter.leftover.capacity(), capacity);
+        }
+    }
+}
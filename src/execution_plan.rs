@@ -0,0 +1,150 @@
+use std::cmp::Ordering;
+
+use clack_host::events::event_types::TransportEvent;
+
+use crate::plugin_processor::{PluginAudioProcessor, PluginId};
+
+pub enum StepKind {
+    Input,
+    Output,
+    Bus,
+    Plugin(PluginId),
+}
+
+pub struct PlanStep {
+    pub kind: StepKind,
+    /// Index of the buffer this step writes into.
+    pub slot: usize,
+    /// Buffers mixed into `slot` before the step runs, with their gain.
+    pub inputs: Vec<(usize, f32)>,
+    /// Plugin input ports and the buffers feeding them.
+    pub sidechains: Vec<(usize, usize)>,
+}
+
+/// A compiled `RoutingGraph`. Steps are topologically sorted and every buffer
+/// is preallocated, so running the plan never allocates.
+pub struct ExecutionPlan {
+    steps: Vec<PlanStep>,
+    buffers: Vec<Vec<Vec<f32>>>,
+    output_slot: Option<usize>,
+    /// Plugins whose notes are played by another plugin.
+    note_routes: Vec<(PluginId, PluginId)>,
+}
+
+impl ExecutionPlan {
+    pub fn new(steps: Vec<PlanStep>, slots: usize, channels: usize, max_frames: usize) -> Self {
+        let output_slot = steps
+            .iter()
+            .find(|step| matches!(step.kind, StepKind::Output))
+            .map(|step| step.slot);
+
+        Self {
+            steps,
+            buffers: vec![vec![vec![0.0; max_frames]; channels]; slots],
+            output_slot,
+            note_routes: vec![],
+        }
+    }
+
+    pub fn empty(channels: usize, max_frames: usize) -> Self {
+        Self::new(vec![], 0, channels, max_frames)
+    }
+
+    /// Notes reach plugins running later in the same block, or earlier ones
+    /// in the next block.
+    pub fn set_note_routes(&mut self, note_routes: Vec<(PluginId, PluginId)>) {
+        self.note_routes = note_routes;
+    }
+
+    pub fn process(
+        &mut self,
+        plugins: &mut [Box<PluginAudioProcessor>],
+        input: &[Vec<f32>],
+        frames: usize,
+        transport: &TransportEvent,
+    ) {
+        for step in &self.steps {
+            for channel in &mut self.buffers[step.slot] {
+                channel[..frames].fill(0.0);
+            }
+
+            for (from, gain) in &step.inputs {
+                mix(&mut self.buffers, *from, step.slot, *gain, frames);
+            }
+
+            match step.kind {
+                StepKind::Input => {
+                    for (channel, buffer) in self.buffers[step.slot].iter_mut().enumerate() {
+                        buffer[..frames].copy_from_slice(&input[channel % input.len()][..frames]);
+                    }
+                }
+                StepKind::Plugin(id) => {
+                    if let Some(plugin) = plugins.iter_mut().find(|plugin| plugin.id == id) {
+                        for (port, from) in &step.sidechains {
+                            plugin.feed_input(*port, &self.buffers[*from], frames);
+                        }
+                        plugin.process_in_place(&mut self.buffers[step.slot], frames, transport);
+                    }
+
+                    for (from, to) in &self.note_routes {
+                        if *from == id {
+                            forward_notes(plugins, *from, *to);
+                        }
+                    }
+                }
+                StepKind::Output | StepKind::Bus => {}
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn steps(&self) -> &[PlanStep] {
+        &self.steps
+    }
+
+    /// The output node's channel, or `None` if nothing is routed to the output.
+    pub fn output(&self, channel: usize) -> Option<&[f32]> {
+        self.output_slot
+            .map(|slot| self.buffers[slot][channel].as_slice())
+    }
+}
+
+fn forward_notes(plugins: &mut [Box<PluginAudioProcessor>], from: PluginId, to: PluginId) {
+    let position = |id| plugins.iter().position(|plugin| plugin.id == id);
+    let (Some(from), Some(to)) = (position(from), position(to)) else {
+        return;
+    };
+
+    let (source, destination) = match from.cmp(&to) {
+        Ordering::Less => {
+            let (head, tail) = plugins.split_at_mut(to);
+            (&head[from], &mut tail[0])
+        }
+        Ordering::Greater => {
+            let (head, tail) = plugins.split_at_mut(from);
+            (&tail[0], &mut head[to])
+        }
+        Ordering::Equal => return,
+    };
+
+    for (time, message) in source.output_midi() {
+        destination.forward_midi(*time, *message);
+    }
+}
+
+fn mix(buffers: &mut [Vec<Vec<f32>>], from: usize, to: usize, gain: f32, frames: usize) {
+    let (source, destination) = if from < to {
+        let (head, tail) = buffers.split_at_mut(to);
+        (&head[from], &mut tail[0])
+    } else {
+        let (head, tail) = buffers.split_at_mut(from);
+        (&tail[0], &mut head[to])
+    };
+
+    for (channel, destination) in destination.iter_mut().enumerate() {
+        let source = &source[channel % source.len()];
+        for (destination, source) in destination[..frames].iter_mut().zip(&source[..frames]) {
+            *destination += source * gain;
+        }
+    }
+}
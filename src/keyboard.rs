@@ -0,0 +1,240 @@
+use egui::{Color32, Event, Key, Pos2, Rect, Sense, Stroke, Vec2};
+
+use crate::notes::{Note, NoteKind};
+
+const OCTAVES: u8 = 2;
+/// C3, with no octave shift.
+const BASE_KEY: i16 = 48;
+const WHITE_KEY_SIZE: Vec2 = Vec2::new(24.0, 100.0);
+const BLACK_KEY_SIZE: Vec2 = Vec2::new(16.0, 60.0);
+const KEYBOARD_VELOCITY: f64 = 0.8;
+/// Keys past the top of a white key still play quietly.
+const MIN_VELOCITY: f64 = 0.1;
+
+/// Semitones of the white keys in an octave.
+const WHITE_KEYS: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+/// Semitones of the black keys, and the white key they sit after.
+const BLACK_KEYS: [(u8, usize); 5] = [(1, 0), (3, 1), (6, 3), (8, 4), (10, 5)];
+
+/// Computer keys laid out like a piano, from the lowest C.
+const KEY_MAP: [(Key, u8); 13] = [
+    (Key::A, 0),
+    (Key::W, 1),
+    (Key::S, 2),
+    (Key::E, 3),
+    (Key::D, 4),
+    (Key::F, 5),
+    (Key::T, 6),
+    (Key::G, 7),
+    (Key::Y, 8),
+    (Key::H, 9),
+    (Key::U, 10),
+    (Key::J, 11),
+    (Key::K, 12),
+];
+
+/// A two octaves piano played with the mouse or the computer keyboard. Z and
+/// X shift the octave.
+#[derive(Default)]
+pub struct PianoKeyboard {
+    octave: i8,
+    mouse_key: Option<u8>,
+    /// Remembered with their notes, so releasing a key after an octave shift
+    /// stops the right note.
+    held_keys: Vec<(Key, u8)>,
+}
+
+impl PianoKeyboard {
+    /// Draws the keyboard and returns the notes played since the last frame.
+    pub fn ui(&mut self, ui: &mut egui::Ui) -> Vec<Note> {
+        let mut notes = vec![];
+
+        ui.horizontal(|ui| {
+            if ui.button("-").clicked() {
+                self.shift_octave(-1);
+            }
+            ui.label(format!("Octave {:+}", self.octave));
+            if ui.button("+").clicked() {
+                self.shift_octave(1);
+            }
+        });
+
+        let white_keys_count = WHITE_KEYS.len() * OCTAVES as usize;
+        let size = Vec2::new(WHITE_KEY_SIZE.x * white_keys_count as f32, WHITE_KEY_SIZE.y);
+        let (rect, response) = ui.allocate_exact_size(size, Sense::click_and_drag());
+
+        let pointer = response
+            .interact_pointer_pos()
+            .filter(|_| response.is_pointer_button_down_on());
+        let hovered = pointer.and_then(|pos| self.key_at(rect, pos));
+
+        if hovered.map(|(key, _)| key) != self.mouse_key {
+            if let Some(key) = self.mouse_key.take() {
+                notes.push(note(NoteKind::Off, key, 0.0));
+            }
+            if let Some((key, velocity)) = hovered {
+                notes.push(note(NoteKind::On, key, velocity));
+                self.mouse_key = Some(key);
+            }
+        }
+
+        let is_focused = ui.input(|input| input.focused);
+        if is_focused && !ui.ctx().wants_keyboard_input() {
+            self.handle_keys(ui, &mut notes);
+        } else {
+            // The key releases would never reach us
+            self.release_keys(&mut notes);
+        }
+
+        self.paint(ui, rect);
+
+        notes
+    }
+
+    /// Stops every held note, e.g. before the keyboard switches plugins.
+    pub fn choke_all(&mut self) -> Vec<Note> {
+        self.mouse_key
+            .take()
+            .into_iter()
+            .chain(self.held_keys.drain(..).map(|(_, key)| key))
+            .map(|key| note(NoteKind::Choke, key, 0.0))
+            .collect()
+    }
+
+    fn release_keys(&mut self, notes: &mut Vec<Note>) {
+        notes.extend(
+            self.held_keys
+                .drain(..)
+                .map(|(_, key)| note(NoteKind::Off, key, 0.0)),
+        );
+    }
+
+    fn handle_keys(&mut self, ui: &egui::Ui, notes: &mut Vec<Note>) {
+        let events = ui.input(|input| input.events.clone());
+
+        for event in events {
+            let Event::Key {
+                key,
+                pressed,
+                repeat: false,
+                ..
+            } = event
+            else {
+                continue;
+            };
+
+            match (key, pressed) {
+                (Key::Z, true) => self.shift_octave(-1),
+                (Key::X, true) => self.shift_octave(1),
+                (key, true) => {
+                    let Some((_, semitone)) = KEY_MAP.iter().find(|(mapped, _)| *mapped == key)
+                    else {
+                        continue;
+                    };
+                    let Some(note_key) = self.key(*semitone) else {
+                        continue;
+                    };
+
+                    self.held_keys.push((key, note_key));
+                    notes.push(note(NoteKind::On, note_key, KEYBOARD_VELOCITY));
+                }
+                (key, false) => {
+                    let Some(index) = self.held_keys.iter().position(|(held, _)| *held == key)
+                    else {
+                        continue;
+                    };
+
+                    let (_, note_key) = self.held_keys.remove(index);
+                    notes.push(note(NoteKind::Off, note_key, 0.0));
+                }
+            }
+        }
+    }
+
+    fn shift_octave(&mut self, shift: i8) {
+        self.octave = (self.octave + shift).clamp(-3, 4);
+    }
+
+    /// The MIDI key of a semitone counted from the lowest C.
+    fn key(&self, semitone: u8) -> Option<u8> {
+        let key = BASE_KEY + self.octave as i16 * 12 + semitone as i16;
+        u8::try_from(key).ok().filter(|key| *key <= 127)
+    }
+
+    fn white_key_rect(rect: Rect, index: usize) -> Rect {
+        Rect::from_min_size(
+            rect.min + Vec2::new(WHITE_KEY_SIZE.x * index as f32, 0.0),
+            WHITE_KEY_SIZE,
+        )
+    }
+
+    fn black_key_rect(rect: Rect, after_white_key: usize) -> Rect {
+        let x = WHITE_KEY_SIZE.x * (after_white_key + 1) as f32 - BLACK_KEY_SIZE.x / 2.0;
+        Rect::from_min_size(rect.min + Vec2::new(x, 0.0), BLACK_KEY_SIZE)
+    }
+
+    /// Every key with its rect, black keys first since they're on top.
+    fn keys(&self, rect: Rect) -> Vec<(u8, Rect, bool)> {
+        let mut keys = vec![];
+
+        for octave in 0..OCTAVES {
+            for (semitone, after) in BLACK_KEYS {
+                if let Some(key) = self.key(octave * 12 + semitone) {
+                    let index = octave as usize * WHITE_KEYS.len() + after;
+                    keys.push((key, Self::black_key_rect(rect, index), true));
+                }
+            }
+        }
+
+        for octave in 0..OCTAVES {
+            for (index, semitone) in WHITE_KEYS.into_iter().enumerate() {
+                if let Some(key) = self.key(octave * 12 + semitone) {
+                    let index = octave as usize * WHITE_KEYS.len() + index;
+                    keys.push((key, Self::white_key_rect(rect, index), false));
+                }
+            }
+        }
+
+        keys
+    }
+
+    /// The key under the pointer, and a velocity growing towards the bottom
+    /// of the key.
+    fn key_at(&self, rect: Rect, pos: Pos2) -> Option<(u8, f64)> {
+        self.keys(rect)
+            .into_iter()
+            .find(|(_, key_rect, _)| key_rect.contains(pos))
+            .map(|(key, key_rect, _)| {
+                let velocity = ((pos.y - key_rect.top()) / key_rect.height()) as f64;
+                (key, velocity.clamp(MIN_VELOCITY, 1.0))
+            })
+    }
+
+    fn paint(&self, ui: &egui::Ui, rect: Rect) {
+        let painter = ui.painter_at(rect);
+        let is_held = |key: u8| {
+            self.mouse_key == Some(key) || self.held_keys.iter().any(|(_, held)| *held == key)
+        };
+        let held_color = ui.visuals().selection.bg_fill;
+
+        // Painted in reverse so black keys end up on top
+        for (key, key_rect, is_black) in self.keys(rect).into_iter().rev() {
+            let fill = match (is_held(key), is_black) {
+                (true, _) => held_color,
+                (false, true) => Color32::BLACK,
+                (false, false) => Color32::WHITE,
+            };
+
+            painter.rect(key_rect, 2.0, fill, Stroke::new(1.0, Color32::DARK_GRAY));
+        }
+    }
+}
+
+fn note(kind: NoteKind, key: u8, velocity: f64) -> Note {
+    Note {
+        kind,
+        channel: 0,
+        key,
+        velocity,
+    }
+}
@@ -2,7 +2,24 @@
 
 mod app;
 mod audio;
+mod audio_input;
 mod audio_io;
+mod block_splitter;
+mod execution_plan;
+mod keyboard;
+mod midi_file;
+mod midi_player;
+mod midi_recorder;
+mod notes;
+mod plugin_bundle;
 mod plugin_host;
+mod plugin_processor;
 mod plugins_container;
+mod resampler;
+mod routing_graph;
+mod sample_writer;
+mod scan_cache;
+mod scanner;
+mod transport;
 pub use app::TemplateApp;
+pub use scan_cache::{run_prober, PROBE_ARG};
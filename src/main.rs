@@ -4,6 +4,12 @@
 // When compiling natively:
 #[cfg(not(target_arch = "wasm32"))]
 fn main() -> eframe::Result<()> {
+    // The plugin scanner re-runs this binary to probe each bundle in isolation
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some(eframe_template::PROBE_ARG) {
+        eframe_template::run_prober(&args.next().unwrap_or_default());
+    }
+
     // Log to stdout (if you run with `RUST_LOG=debug`).
     tracing_subscriber::fmt::init();
 
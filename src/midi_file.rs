@@ -0,0 +1,503 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs,
+};
+
+#[derive(Clone, Copy)]
+pub enum MidiFileEventKind {
+    /// A channel message. Program changes and channel pressure only use the
+    /// first data byte.
+    Message([u8; 3]),
+    /// Microseconds per quarter note.
+    Tempo(u32),
+}
+
+#[derive(Clone, Copy)]
+pub struct MidiFileEvent {
+    pub tick: u64,
+    pub kind: MidiFileEventKind,
+}
+
+pub enum Timing {
+    TicksPerQuarter(u16),
+    /// SMPTE time, in ticks per second.
+    TicksPerSecond(f64),
+}
+
+/// A Standard MIDI File with all tracks merged into one list of events,
+/// sorted by tick.
+pub struct MidiFile {
+    pub timing: Timing,
+    pub events: Vec<MidiFileEvent>,
+    pub length: u64,
+}
+
+#[derive(Debug)]
+pub enum MidiFileError {
+    Io(std::io::Error),
+    NotAMidiFile,
+    UnexpectedEnd,
+    InvalidEvent(u64),
+}
+
+impl Display for MidiFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "Failed to read the file: {err}"),
+            Self::NotAMidiFile => write!(f, "This isn't a Standard MIDI File"),
+            Self::UnexpectedEnd => write!(f, "The file is truncated"),
+            Self::InvalidEvent(tick) => write!(f, "Invalid event at tick {tick}"),
+        }
+    }
+}
+
+impl Error for MidiFileError {}
+
+impl MidiFile {
+    pub fn load(path: &str) -> Result<Self, MidiFileError> {
+        let bytes = fs::read(path).map_err(MidiFileError::Io)?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, MidiFileError> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.take(4)? != b"MThd" {
+            return Err(MidiFileError::NotAMidiFile);
+        }
+        let header_length = reader.u32()? as usize;
+        let header = reader.take(header_length)?;
+        if header.len() < 6 {
+            return Err(MidiFileError::NotAMidiFile);
+        }
+
+        let tracks = u16::from_be_bytes([header[2], header[3]]);
+        let division = u16::from_be_bytes([header[4], header[5]]);
+        let timing = if division & 0x8000 == 0 {
+            Timing::TicksPerQuarter(division.max(1))
+        } else {
+            let frames_per_second = match (division >> 8) as u8 as i8 {
+                -29 => 29.97,
+                fps => -(fps as f64),
+            };
+            Timing::TicksPerSecond(frames_per_second * (division & 0xFF).max(1) as f64)
+        };
+
+        let mut events = vec![];
+        let mut length = 0;
+
+        for _ in 0..tracks {
+            // Unknown chunks are skipped
+            let chunk = loop {
+                let id = reader.take(4)?;
+                let chunk_length = reader.u32()? as usize;
+                let chunk = reader.take(chunk_length)?;
+                if id == b"MTrk" {
+                    break chunk;
+                }
+            };
+
+            let end = parse_track(chunk, &mut events)?;
+            length = length.max(end);
+        }
+
+        // Stable, so events on the same tick keep their track order
+        events.sort_by_key(|event| event.tick);
+
+        Ok(Self {
+            timing,
+            events,
+            length,
+        })
+    }
+}
+
+impl MidiFile {
+    /// Writes the events as a single track file.
+    pub fn save(&self, path: &str) -> Result<(), MidiFileError> {
+        fs::write(path, self.to_bytes()).map_err(MidiFileError::Io)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut track = vec![];
+        let mut last_tick = 0;
+
+        for event in &self.events {
+            write_variable_length(&mut track, event.tick.saturating_sub(last_tick));
+            last_tick = last_tick.max(event.tick);
+
+            match event.kind {
+                MidiFileEventKind::Message(message) => {
+                    let length = match message[0] & 0xF0 {
+                        0xC0 | 0xD0 => 2,
+                        _ => 3,
+                    };
+                    track.extend_from_slice(&message[..length]);
+                }
+                MidiFileEventKind::Tempo(tempo) => {
+                    track.extend_from_slice(&[0xFF, 0x51, 3]);
+                    track.extend_from_slice(&tempo.to_be_bytes()[1..]);
+                }
+            }
+        }
+
+        write_variable_length(&mut track, self.length.saturating_sub(last_tick));
+        track.extend_from_slice(&[0xFF, 0x2F, 0]);
+
+        // SMPTE time is written at 25 frames per second
+        let division = match self.timing {
+            Timing::TicksPerQuarter(ticks_per_quarter) => ticks_per_quarter & 0x7FFF,
+            Timing::TicksPerSecond(ticks_per_second) => {
+                let ticks_per_frame = (ticks_per_second / 25.0).round().clamp(1.0, 255.0);
+                u16::from_be_bytes([-25i8 as u8, ticks_per_frame as u8])
+            }
+        };
+
+        let mut bytes = vec![];
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        // Format 0, one track
+        bytes.extend_from_slice(&0u16.to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&division.to_be_bytes());
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+
+        bytes
+    }
+}
+
+/// Longer delays than a variable length quantity holds are shortened.
+fn write_variable_length(bytes: &mut Vec<u8>, value: u64) {
+    let value = value.min(0x0FFF_FFFF);
+    let mut shift = 21;
+
+    while shift > 0 && value >> shift == 0 {
+        shift -= 7;
+    }
+    while shift > 0 {
+        bytes.push((value >> shift) as u8 & 0x7F | 0x80);
+        shift -= 7;
+    }
+    bytes.push(value as u8 & 0x7F);
+}
+
+/// Appends the track's events and returns its length in ticks.
+fn parse_track(chunk: &[u8], events: &mut Vec<MidiFileEvent>) -> Result<u64, MidiFileError> {
+    let mut reader = Reader {
+        bytes: chunk,
+        position: 0,
+    };
+    let mut tick = 0;
+    let mut running_status = None;
+
+    while !reader.is_at_end() {
+        tick += reader.variable_length()? as u64;
+
+        let mut status = reader.u8()?;
+        let first_data = if status < 0x80 {
+            // Running status: the byte was already data
+            let data = status;
+            status = running_status.ok_or(MidiFileError::InvalidEvent(tick))?;
+            Some(data)
+        } else {
+            None
+        };
+
+        match status {
+            0xFF => {
+                running_status = None;
+                let meta_type = reader.u8()?;
+                let length = reader.variable_length()? as usize;
+                let data = reader.take(length)?;
+
+                match meta_type {
+                    0x2F => break,
+                    0x51 if data.len() == 3 => events.push(MidiFileEvent {
+                        tick,
+                        kind: MidiFileEventKind::Tempo(u32::from_be_bytes([
+                            0, data[0], data[1], data[2],
+                        ])),
+                    }),
+                    _ => {}
+                }
+            }
+            0xF0 | 0xF7 => {
+                running_status = None;
+                let length = reader.variable_length()? as usize;
+                reader.take(length)?;
+            }
+            0x80..=0xEF => {
+                running_status = Some(status);
+
+                let data1 = match first_data {
+                    Some(data) => data,
+                    None => reader.u8()?,
+                };
+                let data2 = match status & 0xF0 {
+                    0xC0 | 0xD0 => 0,
+                    _ => reader.u8()?,
+                };
+
+                events.push(MidiFileEvent {
+                    tick,
+                    kind: MidiFileEventKind::Message([status, data1, data2]),
+                });
+            }
+            _ => return Err(MidiFileError::InvalidEvent(tick)),
+        }
+    }
+
+    Ok(tick)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_at_end(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], MidiFileError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + length)
+            .ok_or(MidiFileError::UnexpectedEnd)?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, MidiFileError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, MidiFileError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// At most four bytes, seven bits each, the last one without the high bit.
+    fn variable_length(&mut self) -> Result<u32, MidiFileError> {
+        let mut value = 0;
+
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(MidiFileError::InvalidEvent(value as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn midi_file(division: u16, track: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 1]);
+        bytes.extend_from_slice(&division.to_be_bytes());
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(track);
+        bytes
+    }
+
+    fn message(event: &MidiFileEvent) -> Option<[u8; 3]> {
+        match event.kind {
+            MidiFileEventKind::Message(message) => Some(message),
+            MidiFileEventKind::Tempo(_) => None,
+        }
+    }
+
+    const END_OF_TRACK: [u8; 4] = [0, 0xFF, 0x2F, 0];
+
+    #[test]
+    fn parses_ticks_per_quarter() {
+        let file = MidiFile::parse(&midi_file(96, &END_OF_TRACK)).unwrap();
+
+        assert!(matches!(file.timing, Timing::TicksPerQuarter(96)));
+        assert!(file.events.is_empty());
+        assert_eq!(file.length, 0);
+    }
+
+    #[test]
+    fn parses_smpte_division() {
+        let division = u16::from_be_bytes([-25i8 as u8, 40]);
+        let file = MidiFile::parse(&midi_file(division, &END_OF_TRACK)).unwrap();
+        assert!(matches!(file.timing, Timing::TicksPerSecond(ticks) if ticks == 1000.0));
+
+        let division = u16::from_be_bytes([-29i8 as u8, 4]);
+        let file = MidiFile::parse(&midi_file(division, &END_OF_TRACK)).unwrap();
+        assert!(matches!(file.timing, Timing::TicksPerSecond(ticks) if ticks == 29.97 * 4.0));
+    }
+
+    #[test]
+    fn follows_running_status() {
+        let track = [
+            0x00, 0x90, 60, 100, //
+            0x10, 64, 90, // running note on
+            0x20, 0x80, 60, 0, //
+            0x00, 64, 0, // running note off
+            0x00, 0xC1, 5, // program change, one data byte
+            0x00, 7, // running program change
+            0x00, 0xFF, 0x2F, 0,
+        ];
+        let file = MidiFile::parse(&midi_file(96, &track)).unwrap();
+
+        let events: Vec<_> = file
+            .events
+            .iter()
+            .map(|event| (event.tick, message(event).unwrap()))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (0, [0x90, 60, 100]),
+                (16, [0x90, 64, 90]),
+                (48, [0x80, 60, 0]),
+                (48, [0x80, 64, 0]),
+                (48, [0xC1, 5, 0]),
+                (48, [0xC1, 7, 0]),
+            ]
+        );
+        assert_eq!(file.length, 48);
+    }
+
+    #[test]
+    fn running_status_needs_a_status() {
+        let track = [0x00, 60, 100, 0x00, 0xFF, 0x2F, 0];
+
+        assert!(matches!(
+            MidiFile::parse(&midi_file(96, &track)),
+            Err(MidiFileError::InvalidEvent(0))
+        ));
+    }
+
+    #[test]
+    fn reads_tempo_meta_events() {
+        let track = [
+            0x00, 0xFF, 0x51, 3, 0x07, 0xA1, 0x20, // 120 BPM
+            0x60, 0xFF, 0x51, 3, 0x0F, 0x42, 0x40, // 60 BPM
+            0x00, 0xFF, 0x2F, 0,
+        ];
+        let file = MidiFile::parse(&midi_file(96, &track)).unwrap();
+
+        let tempos: Vec<_> = file
+            .events
+            .iter()
+            .map(|event| match event.kind {
+                MidiFileEventKind::Tempo(tempo) => (event.tick, tempo),
+                MidiFileEventKind::Message(_) => panic!("not a tempo"),
+            })
+            .collect();
+        assert_eq!(tempos, [(0, 500_000), (96, 1_000_000)]);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let bytes = midi_file(96, &[0x00, 0x90, 60, 100, 0x00, 0xFF, 0x2F, 0]);
+
+        assert!(matches!(
+            MidiFile::parse(&bytes[..10]),
+            Err(MidiFileError::UnexpectedEnd)
+        ));
+        // The track chunk is shorter than its length says
+        assert!(matches!(
+            MidiFile::parse(&bytes[..bytes.len() - 2]),
+            Err(MidiFileError::UnexpectedEnd)
+        ));
+
+        // An event cut short inside the track
+        let track = [0x00, 0x90, 60];
+        assert!(matches!(
+            MidiFile::parse(&midi_file(96, &track)),
+            Err(MidiFileError::UnexpectedEnd)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(
+            MidiFile::parse(b"RIFF\0\0\0\x06\0\0\0\0\0\0"),
+            Err(MidiFileError::NotAMidiFile)
+        ));
+    }
+
+    #[test]
+    fn writes_variable_length_quantities() {
+        let encode = |value| {
+            let mut bytes = vec![];
+            write_variable_length(&mut bytes, value);
+            bytes
+        };
+
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(0x7F), [0x7F]);
+        assert_eq!(encode(0x80), [0x81, 0x00]);
+        assert_eq!(encode(0x3FFF), [0xFF, 0x7F]);
+        assert_eq!(encode(0x4000), [0x81, 0x80, 0x00]);
+        assert_eq!(encode(0x0FFF_FFFF), [0xFF, 0xFF, 0xFF, 0x7F]);
+        // Clamped to what four bytes hold
+        assert_eq!(encode(0x1000_0000), [0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let deltas = [
+            0,
+            0x7F,
+            0x80,
+            0x3FFF,
+            0x4000,
+            0x1F_FFFF,
+            0x20_0000,
+            0x0FFF_FFFF,
+        ];
+        let mut tick = 0;
+        let mut events = vec![MidiFileEvent {
+            tick: 0,
+            kind: MidiFileEventKind::Tempo(400_000),
+        }];
+        for (index, delta) in deltas.into_iter().enumerate() {
+            tick += delta;
+            events.push(MidiFileEvent {
+                tick,
+                kind: MidiFileEventKind::Message([0x90, index as u8, 100]),
+            });
+        }
+        events.push(MidiFileEvent {
+            tick,
+            kind: MidiFileEventKind::Message([0xC0, 3, 0]),
+        });
+
+        let file = MidiFile {
+            timing: Timing::TicksPerQuarter(480),
+            events,
+            length: tick + 10,
+        };
+        let parsed = MidiFile::parse(&file.to_bytes()).unwrap();
+
+        assert!(matches!(parsed.timing, Timing::TicksPerQuarter(480)));
+        assert_eq!(parsed.length, file.length);
+        assert_eq!(parsed.events.len(), file.events.len());
+        assert!(matches!(
+            parsed.events[0].kind,
+            MidiFileEventKind::Tempo(400_000)
+        ));
+        for (parsed, written) in parsed.events.iter().zip(&file.events).skip(1) {
+            assert_eq!(parsed.tick, written.tick);
+            assert_eq!(message(parsed), message(written));
+        }
+    }
+}
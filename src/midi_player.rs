@@ -0,0 +1,487 @@
+use crate::{
+    midi_file::{MidiFile, MidiFileEventKind, Timing},
+    plugin_processor::PluginId,
+    transport::Transport,
+};
+
+const ALL_CHANNELS: u16 = 0xFFFF;
+
+/// What the UI controls, kept on the main thread to build the player with.
+#[derive(Clone, Copy, PartialEq)]
+pub struct PlayerSettings {
+    pub target: Option<PluginId>,
+    pub is_looping: bool,
+    /// In BPM, replaces the tempo of the transport.
+    pub tempo: Option<f64>,
+    /// One bit per MIDI channel.
+    pub channels: u16,
+}
+
+impl Default for PlayerSettings {
+    fn default() -> Self {
+        Self {
+            target: None,
+            is_looping: false,
+            tempo: None,
+            channels: ALL_CHANNELS,
+        }
+    }
+}
+
+/// Plays a MIDI file on the audio thread along with the transport, timing
+/// every event to the frame. Quarter notes of the file are the transport's
+/// beats, its own tempo changes are ignored.
+pub struct MidiPlayer {
+    file: MidiFile,
+    settings: PlayerSettings,
+    was_playing: bool,
+    /// Whether the position was found from the transport yet.
+    is_synced: bool,
+    /// Reached the end without looping.
+    is_finished: bool,
+    tick: f64,
+    next_event: usize,
+    active_notes: [[bool; 128]; 16],
+    /// Notes to stop at the start of the next block, and who plays them.
+    release: Option<PluginId>,
+}
+
+impl MidiPlayer {
+    pub fn new(file: MidiFile, settings: PlayerSettings) -> Self {
+        Self {
+            file,
+            settings,
+            was_playing: false,
+            is_synced: false,
+            is_finished: false,
+            tick: 0.0,
+            next_event: 0,
+            active_notes: [[false; 128]; 16],
+            release: None,
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: PlayerSettings) {
+        // Filtered channels or a new target would leave notes hanging
+        if settings.target != self.settings.target || settings.channels != self.settings.channels {
+            self.release = self.settings.target;
+        }
+        self.settings = settings;
+    }
+
+    /// Emits the MIDI messages of the next `frames` frames, each with its
+    /// target and its frame offset in the block. The file starts with the
+    /// transport's timeline, it follows its jumps and stops.
+    ///
+    /// `emit` returns `false` when the target has no room left. The message
+    /// is emitted again at the start of the next block, with the ones after
+    /// it.
+    pub fn render(
+        &mut self,
+        frames: usize,
+        sample_rate: f64,
+        transport: &Transport,
+        mut emit: impl FnMut(PluginId, u32, [u8; 3]) -> bool,
+    ) {
+        let is_stopping = self.was_playing && !transport.is_playing();
+        self.was_playing = transport.is_playing();
+
+        if is_stopping || transport.has_jumped() {
+            self.release = self.release.or(self.settings.target);
+        }
+        if let Some(target) = self.release.take() {
+            self.release_notes(target, 0, &mut emit);
+        }
+
+        if transport.has_jumped() || !self.is_synced {
+            self.seek(self.transport_ticks(transport));
+            self.is_synced = true;
+        }
+
+        if !transport.is_playing() || self.is_finished {
+            return;
+        }
+
+        let frames = frames as f64;
+        let ticks_per_frame = self.ticks_per_frame(transport, sample_rate);
+        let mut frame = 0.0;
+
+        loop {
+            let next_tick = self
+                .file
+                .events
+                .get(self.next_event)
+                .map_or(self.file.length, |event| event.tick) as f64;
+            let frames_to_next = ((next_tick - self.tick) / ticks_per_frame).max(0.0);
+
+            if frame + frames_to_next >= frames {
+                self.tick += (frames - frame) * ticks_per_frame;
+                return;
+            }
+
+            frame += frames_to_next;
+            self.tick = next_tick;
+            let time = frame as u32;
+
+            let Some(event) = self.file.events.get(self.next_event).copied() else {
+                if let Some(target) = self.settings.target {
+                    self.release_notes(target, time, &mut emit);
+                }
+
+                if !self.settings.is_looping || self.file.length == 0 {
+                    self.is_finished = true;
+                    return;
+                }
+                self.rewind();
+                continue;
+            };
+
+            let MidiFileEventKind::Message(message) = event.kind else {
+                self.next_event += 1;
+                continue;
+            };
+            let channel = (message[0] & 0x0F) as usize;
+            if self.settings.channels & (1 << channel) == 0 {
+                self.next_event += 1;
+                continue;
+            }
+
+            if let Some(target) = self.settings.target {
+                if !emit(target, time, message) {
+                    // Overdue by then, so it comes first
+                    self.tick += (frames - frame) * ticks_per_frame;
+                    return;
+                }
+            }
+            self.next_event += 1;
+
+            let key = (message[1] & 0x7F) as usize;
+            match message[0] & 0xF0 {
+                0x90 if message[2] > 0 => self.active_notes[channel][key] = true,
+                0x80 | 0x90 => self.active_notes[channel][key] = false,
+                _ => {}
+            }
+        }
+    }
+
+    fn rewind(&mut self) {
+        self.tick = 0.0;
+        self.next_event = 0;
+        self.is_finished = false;
+    }
+
+    /// Moves to a position in ticks, wrapped into the file when it loops.
+    /// Skipped notes aren't played.
+    fn seek(&mut self, tick: f64) {
+        self.rewind();
+
+        let length = self.file.length as f64;
+        self.tick = if self.settings.is_looping && length > 0.0 {
+            tick % length
+        } else {
+            tick
+        };
+        self.next_event = self
+            .file
+            .events
+            .partition_point(|event| (event.tick as f64) < self.tick);
+    }
+
+    /// Where the transport is in the file.
+    fn transport_ticks(&self, transport: &Transport) -> f64 {
+        match self.file.timing {
+            Timing::TicksPerQuarter(ticks_per_quarter) => {
+                let speed = self.tempo(transport) / transport.tempo();
+                transport.beats() * ticks_per_quarter as f64 * speed
+            }
+            Timing::TicksPerSecond(ticks_per_second) => {
+                transport.seconds() * ticks_per_second * self.smpte_scale()
+            }
+        }
+    }
+
+    fn ticks_per_frame(&self, transport: &Transport, sample_rate: f64) -> f64 {
+        match self.file.timing {
+            Timing::TicksPerQuarter(ticks_per_quarter) => {
+                ticks_per_quarter as f64 * self.tempo(transport) / 60.0 / sample_rate
+            }
+            Timing::TicksPerSecond(ticks_per_second) => {
+                ticks_per_second * self.smpte_scale() / sample_rate
+            }
+        }
+    }
+
+    fn tempo(&self, transport: &Transport) -> f64 {
+        self.settings.tempo.unwrap_or_else(|| transport.tempo())
+    }
+
+    /// SMPTE files have no beats, they play in real time and the override
+    /// scales from 120 BPM.
+    fn smpte_scale(&self) -> f64 {
+        self.settings.tempo.map_or(1.0, |bpm| bpm / 120.0)
+    }
+
+    /// The notes that don't fit are released in the next block.
+    fn release_notes(
+        &mut self,
+        target: PluginId,
+        time: u32,
+        emit: &mut impl FnMut(PluginId, u32, [u8; 3]) -> bool,
+    ) {
+        for (channel, keys) in self.active_notes.iter_mut().enumerate() {
+            for (key, is_active) in keys.iter_mut().enumerate() {
+                if !*is_active {
+                    continue;
+                }
+                if !emit(target, time, [0x80 | channel as u8, key as u8, 0]) {
+                    self.release = Some(target);
+                    return;
+                }
+                *is_active = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        midi_file::MidiFileEvent,
+        transport::{TransportCommand, TransportSettings},
+    };
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+    const BLOCK: usize = 512;
+    const TARGET: PluginId = 3;
+
+    /// One bar at 480 ticks per quarter: C4 on the first beat, D4 on the
+    /// third.
+    fn file() -> MidiFile {
+        let message = |tick, message| MidiFileEvent {
+            tick,
+            kind: MidiFileEventKind::Message(message),
+        };
+
+        MidiFile {
+            timing: Timing::TicksPerQuarter(480),
+            events: vec![
+                message(0, [0x90, 60, 100]),
+                message(480, [0x80, 60, 0]),
+                MidiFileEvent {
+                    tick: 480,
+                    kind: MidiFileEventKind::Tempo(1_000_000),
+                },
+                message(960, [0x90, 62, 100]),
+                message(1440, [0x80, 62, 0]),
+            ],
+            length: 1920,
+        }
+    }
+
+    fn player(is_looping: bool, tempo: Option<f64>) -> MidiPlayer {
+        MidiPlayer::new(
+            file(),
+            PlayerSettings {
+                target: Some(TARGET),
+                is_looping,
+                tempo,
+                ..PlayerSettings::default()
+            },
+        )
+    }
+
+    fn transport(tempo: f64) -> Transport {
+        let mut transport = Transport::new(Arc::default());
+        transport.handle(TransportCommand::SetSettings(TransportSettings {
+            tempo,
+            ..TransportSettings::default()
+        }));
+        transport.handle(TransportCommand::Play);
+        transport
+    }
+
+    /// Plays `blocks` blocks and returns the messages with the frame they
+    /// were played at since the start.
+    fn play(
+        player: &mut MidiPlayer,
+        transport: &mut Transport,
+        blocks: usize,
+    ) -> Vec<(usize, [u8; 3])> {
+        let mut played = vec![];
+
+        for block in 0..blocks {
+            player.render(BLOCK, SAMPLE_RATE, transport, |target, time, message| {
+                assert_eq!(target, TARGET);
+                played.push((block * BLOCK + time as usize, message));
+                true
+            });
+            transport.advance(BLOCK, SAMPLE_RATE);
+        }
+
+        played
+    }
+
+    fn note_ons(played: &[(usize, [u8; 3])]) -> Vec<(usize, u8)> {
+        played
+            .iter()
+            .filter(|(_, message)| message[0] == 0x90)
+            .map(|(frame, message)| (*frame, message[1]))
+            .collect()
+    }
+
+    /// Frames may be one off from rounding.
+    fn assert_played<T: PartialEq + std::fmt::Debug>(
+        played: &[(usize, T)],
+        expected: &[(usize, T)],
+    ) {
+        assert_eq!(played.len(), expected.len(), "{played:?}");
+        for ((frame, event), (expected_frame, expected_event)) in played.iter().zip(expected) {
+            assert_eq!(event, expected_event);
+            assert!(frame.abs_diff(*expected_frame) <= 1, "{played:?}");
+        }
+    }
+
+    #[test]
+    fn follows_the_transport_tempo() {
+        let mut player = player(false, None);
+        let mut transport = transport(140.0);
+
+        let played = play(&mut player, &mut transport, 100);
+
+        // The third beat, at 140 BPM whatever the file's tempo
+        let third_beat = (2.0 * 60.0 / 140.0 * SAMPLE_RATE) as usize;
+        assert_played(&note_ons(&played), &[(0, 60), (third_beat, 62)]);
+    }
+
+    #[test]
+    fn plays_at_the_tempo_override() {
+        let mut player = player(false, Some(60.0));
+        let mut transport = transport(120.0);
+
+        let played = play(&mut player, &mut transport, 200);
+
+        assert_played(&note_ons(&played), &[(0, 60), (2 * 48_000, 62)]);
+    }
+
+    #[test]
+    fn starts_at_the_transport_position() {
+        let mut player = player(false, None);
+        let mut transport = transport(120.0);
+        transport.handle(TransportCommand::Seek(1.5));
+
+        let played = play(&mut player, &mut transport, 100);
+
+        // Half a beat to the third one, the first note is skipped
+        assert_played(
+            &played,
+            &[(12_000, [0x90, 62, 100]), (36_000, [0x80, 62, 0])],
+        );
+    }
+
+    #[test]
+    fn stops_at_the_end_without_looping() {
+        let mut player = player(false, None);
+        let mut transport = transport(120.0);
+
+        let played = play(&mut player, &mut transport, 500);
+
+        assert_eq!(played.len(), 4);
+        assert!(player.is_finished);
+    }
+
+    #[test]
+    fn loops_back_to_the_start() {
+        let mut player = player(true, None);
+        let mut transport = transport(120.0);
+
+        // A bar is 4 beats, 96000 frames at 120 BPM
+        let played = play(&mut player, &mut transport, 370);
+
+        assert_played(
+            &note_ons(&played),
+            &[(0, 60), (48_000, 62), (96_000, 60), (144_000, 62)],
+        );
+    }
+
+    #[test]
+    fn seeks_into_the_loop() {
+        let mut player = player(true, None);
+        let mut transport = transport(120.0);
+        // A thousand bars in, and a beat
+        transport.handle(TransportCommand::Seek(4_001.0));
+
+        let played = play(&mut player, &mut transport, 50);
+
+        assert_played(&note_ons(&played), &[(24_000, 62)]);
+    }
+
+    #[test]
+    fn retries_what_the_target_has_no_room_for() {
+        let mut file = file();
+        file.events = (0..20)
+            .map(|key| MidiFileEvent {
+                tick: 0,
+                kind: MidiFileEventKind::Message([0x90, 40 + key, 100]),
+            })
+            .collect();
+        let mut player = MidiPlayer::new(
+            file,
+            PlayerSettings {
+                target: Some(TARGET),
+                ..PlayerSettings::default()
+            },
+        );
+        let mut transport = transport(120.0);
+        let mut played = vec![];
+
+        for block in 0..3 {
+            let mut room = 8;
+            player.render(BLOCK, SAMPLE_RATE, &transport, |_, time, message| {
+                if room == 0 {
+                    return false;
+                }
+                room -= 1;
+                played.push((block, time, message[1]));
+                true
+            });
+            transport.advance(BLOCK, SAMPLE_RATE);
+        }
+
+        let keys: Vec<_> = played.iter().map(|(_, _, key)| *key).collect();
+        assert_eq!(keys, (40..60).collect::<Vec<_>>());
+        assert!(played.iter().all(|(_, time, _)| *time == 0));
+        assert_eq!(played[8].0, 1);
+        assert_eq!(played[16].0, 2);
+
+        // Every note is released, however many fit at once
+        transport.handle(TransportCommand::Stop);
+        let mut released = vec![];
+        for _ in 0..3 {
+            let mut room = 8;
+            player.render(BLOCK, SAMPLE_RATE, &transport, |_, _, message| {
+                if room == 0 {
+                    return false;
+                }
+                room -= 1;
+                released.push(message[1]);
+                true
+            });
+        }
+        assert_eq!(released, (40..60).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn releases_notes_when_the_transport_stops() {
+        let mut player = player(false, None);
+        let mut transport = transport(120.0);
+
+        play(&mut player, &mut transport, 10);
+        transport.handle(TransportCommand::Stop);
+        let played = play(&mut player, &mut transport, 1);
+
+        assert_eq!(played, [(0, [0x80, 60, 0])]);
+    }
+}
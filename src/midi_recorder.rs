@@ -0,0 +1,163 @@
+use crate::{
+    midi_file::{MidiFile, MidiFileEvent, MidiFileEventKind, Timing},
+    notes::CapturedMidi,
+    plugin_processor::PluginId,
+};
+
+const TICKS_PER_QUARTER: u16 = 480;
+
+/// Collects what plugins play into a MIDI file, placed on the transport's
+/// timeline with its tempo changes. Plugins only get captured while the
+/// transport plays.
+pub struct MidiRecorder {
+    /// `None` to record every plugin.
+    source: Option<PluginId>,
+    /// The last tempo written, in BPM.
+    tempo: Option<f64>,
+    events: Vec<MidiFileEvent>,
+}
+
+impl MidiRecorder {
+    pub fn new(source: Option<PluginId>) -> Self {
+        Self {
+            source,
+            tempo: None,
+            events: vec![],
+        }
+    }
+
+    pub fn record(&mut self, captured: &[CapturedMidi]) {
+        for event in captured {
+            if self
+                .source
+                .map_or(false, |source| source != event.plugin_id)
+            {
+                continue;
+            }
+            // A file only takes channel messages as is, system messages like
+            // clock or sysex have their own encoding
+            if !(0x80..=0xEF).contains(&event.message[0]) {
+                continue;
+            }
+
+            // Beats are quarter notes
+            let tick = (event.beats * TICKS_PER_QUARTER as f64).round() as u64;
+
+            if self.tempo != Some(event.tempo) {
+                self.tempo = Some(event.tempo);
+                self.events.push(MidiFileEvent {
+                    tick,
+                    kind: MidiFileEventKind::Tempo((60_000_000.0 / event.tempo).round() as u32),
+                });
+            }
+            self.events.push(MidiFileEvent {
+                tick,
+                kind: MidiFileEventKind::Message(event.message),
+            });
+        }
+    }
+
+    /// The recorded messages, not counting tempo changes.
+    pub fn events_count(&self) -> usize {
+        self.events
+            .iter()
+            .filter(|event| matches!(event.kind, MidiFileEventKind::Message(_)))
+            .count()
+    }
+
+    pub fn into_midi_file(mut self) -> MidiFile {
+        // Seeks and loops take the transport back, the sort is stable so
+        // tempo changes stay before the messages recorded after them
+        self.events.sort_by_key(|event| event.tick);
+
+        MidiFile {
+            timing: Timing::TicksPerQuarter(TICKS_PER_QUARTER),
+            length: self.events.last().map_or(0, |event| event.tick),
+            events: self.events,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captured(beats: f64, message: [u8; 3]) -> CapturedMidi {
+        CapturedMidi {
+            plugin_id: 1,
+            beats,
+            tempo: 120.0,
+            message,
+        }
+    }
+
+    #[test]
+    fn round_trips_without_system_messages() {
+        let mut recorder = MidiRecorder::new(None);
+        recorder.record(&[
+            captured(0.0, [0xF8, 0, 0]),
+            captured(0.0, [0x90, 60, 100]),
+            captured(0.5, [0xFE, 0, 0]),
+            captured(0.5, [0xF0, 0x7E, 0x7F]),
+            captured(1.0, [0xFF, 0, 0]),
+            captured(1.0, [0x80, 60, 0]),
+        ]);
+        assert_eq!(recorder.events_count(), 2);
+
+        let file = MidiFile::parse(&recorder.into_midi_file().to_bytes()).unwrap();
+        let messages: Vec<_> = file
+            .events
+            .iter()
+            .filter_map(|event| match event.kind {
+                MidiFileEventKind::Message(message) => Some((event.tick, message)),
+                MidiFileEventKind::Tempo(_) => None,
+            })
+            .collect();
+
+        assert_eq!(messages, [(0, [0x90, 60, 100]), (480, [0x80, 60, 0])]);
+    }
+
+    #[test]
+    fn writes_the_tempo_of_each_event() {
+        let mut recorder = MidiRecorder::new(None);
+        recorder.record(&[
+            captured(0.0, [0x90, 60, 100]),
+            captured(1.0, [0x80, 60, 0]),
+            CapturedMidi {
+                tempo: 90.0,
+                ..captured(2.0, [0x90, 62, 100])
+            },
+            CapturedMidi {
+                tempo: 90.0,
+                ..captured(3.0, [0x80, 62, 0])
+            },
+        ]);
+        assert_eq!(recorder.events_count(), 4);
+
+        let tempos: Vec<_> = recorder
+            .into_midi_file()
+            .events
+            .iter()
+            .filter_map(|event| match event.kind {
+                MidiFileEventKind::Tempo(tempo) => Some((event.tick, tempo)),
+                MidiFileEventKind::Message(_) => None,
+            })
+            .collect();
+
+        assert_eq!(tempos, [(0, 500_000), (960, 666_667)]);
+    }
+
+    #[test]
+    fn records_only_the_source() {
+        let mut recorder = MidiRecorder::new(Some(2));
+        recorder.record(&[
+            captured(0.0, [0x90, 60, 100]),
+            CapturedMidi {
+                plugin_id: 2,
+                ..captured(0.0, [0x90, 62, 100])
+            },
+        ]);
+
+        assert_eq!(recorder.events_count(), 1);
+    }
+}
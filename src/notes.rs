@@ -0,0 +1,91 @@
+use crate::plugin_processor::PluginId;
+
+/// How many notes can wait for the audio thread.
+pub const NOTE_QUEUE_CAPACITY: usize = 512;
+/// How many messages played by plugins can wait for the main thread.
+pub const CAPTURE_QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NoteKind {
+    On,
+    Off,
+    /// Stops the note immediately, without a release.
+    Choke,
+}
+
+/// A note as the host sees it, turned into the plugin's dialect on the audio
+/// thread.
+#[derive(Clone, Copy)]
+pub struct Note {
+    pub kind: NoteKind,
+    pub channel: u8,
+    pub key: u8,
+    /// `0.0..=1.0`
+    pub velocity: f64,
+}
+
+/// A note on its way to a single plugin.
+#[derive(Clone, Copy)]
+pub struct TargetedNote {
+    pub plugin_id: PluginId,
+    pub note: Note,
+}
+
+/// A MIDI message a plugin played, timed against the transport.
+#[derive(Clone, Copy)]
+pub struct CapturedMidi {
+    pub plugin_id: PluginId,
+    /// The song position in beats.
+    pub beats: f64,
+    /// The tempo the beats were counted at, in BPM.
+    pub tempo: f64,
+    pub message: [u8; 3],
+}
+
+impl Note {
+    /// The note of a MIDI 1.0 note on or off message, if it is one.
+    pub fn from_midi(message: [u8; 3]) -> Option<Self> {
+        let kind = match message[0] & 0xF0 {
+            0x90 if message[2] > 0 => NoteKind::On,
+            0x80 | 0x90 => NoteKind::Off,
+            _ => return None,
+        };
+
+        Some(Self {
+            kind,
+            channel: message[0] & 0x0F,
+            key: message[1] & 0x7F,
+            velocity: message[2] as f64 / 127.0,
+        })
+    }
+
+    /// A MIDI 1.0 message. MIDI has no choke, so it's a note off.
+    pub fn to_midi(&self) -> [u8; 3] {
+        let status = match self.kind {
+            NoteKind::On => 0x90,
+            NoteKind::Off | NoteKind::Choke => 0x80,
+        };
+        let velocity = (self.velocity.clamp(0.0, 1.0) * 127.0).round() as u8;
+
+        [status | (self.channel & 0x0F), self.key & 0x7F, velocity]
+    }
+
+    /// A MIDI 2.0 channel voice message, as a Universal MIDI Packet.
+    pub fn to_midi2(&self) -> [u32; 4] {
+        let status: u32 = match self.kind {
+            NoteKind::On => 0x9,
+            NoteKind::Off | NoteKind::Choke => 0x8,
+        };
+        let velocity = (self.velocity.clamp(0.0, 1.0) * u16::MAX as f64).round() as u32;
+
+        [
+            0x4 << 28
+                | status << 20
+                | ((self.channel & 0x0F) as u32) << 16
+                | (self.key as u32) << 8,
+            velocity << 16,
+            0,
+            0,
+        ]
+    }
+}
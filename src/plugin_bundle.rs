@@ -0,0 +1,49 @@
+use std::ffi::CStr;
+
+use clack_host::prelude::{PluginBundle, PluginDescriptor};
+
+use crate::plugin_host::PluginLoadError;
+
+/// What a bundle's factory tells about one of its plugins, without
+/// instantiating it.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct PluginDescription {
+    pub id: String,
+    pub name: String,
+    pub vendor: String,
+    pub version: String,
+    pub features: Vec<String>,
+}
+
+impl From<&PluginDescriptor<'_>> for PluginDescription {
+    fn from(descriptor: &PluginDescriptor<'_>) -> Self {
+        Self {
+            id: to_string(descriptor.id()),
+            name: to_string(descriptor.name()),
+            vendor: to_string(descriptor.vendor()),
+            version: to_string(descriptor.version()),
+            features: descriptor
+                .features()
+                .map(|feature| feature.to_string_lossy().into_owned())
+                .collect(),
+        }
+    }
+}
+
+pub fn describe_bundle(path: &str) -> Result<Vec<PluginDescription>, PluginLoadError> {
+    let bundle = PluginBundle::load(path).map_err(PluginLoadError::Bundle)?;
+    let plugin_factory = bundle
+        .get_plugin_factory()
+        .ok_or(PluginLoadError::NoFactory)?;
+
+    Ok((0..plugin_factory.plugin_count())
+        .filter_map(|index| plugin_factory.plugin_descriptor(index))
+        .map(|descriptor| (&descriptor).into())
+        .collect())
+}
+
+fn to_string(value: Option<&CStr>) -> String {
+    value
+        .map(|value| value.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
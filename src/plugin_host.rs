@@ -1,36 +1,115 @@
-use std::mem::MaybeUninit;
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    mem::MaybeUninit,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+};
 
 use clack_extensions::{
+    audio_ports::{AudioPortFlags, AudioPortInfoBuffer, AudioPortInfoData, PluginAudioPorts},
+    audio_ports_config::{AudioPortsConfigBuffer, AudioPortsConfiguration, PluginAudioPortsConfig},
     log::{HostLog, HostLogImpl},
+    note_ports::{
+        NoteDialect, NoteDialects, NotePortInfoBuffer, NotePortInfoData, PluginNotePorts,
+    },
     params::{
         info::{ParamInfo, ParamInfoData, ParamInfoFlags},
-        PluginParams,
+        HostParams, HostParamsImplMainThread, HostParamsImplShared, ParamClearFlags,
+        ParamRescanFlags, PluginParams,
     },
 };
 use clack_host::{
     events::event_types::ParamValueEvent,
     prelude::{
-        EventBuffer, EventHeader, Host, HostExtensions, HostInfo, HostShared, InputEvents,
-        OutputEvents, PluginAudioConfiguration, PluginBundle, PluginInstance,
+        EventBuffer, EventHeader, Host, HostError, HostExtensions, HostInfo, HostMainThread,
+        HostShared, InputEvents, OutputEvents, PluginAudioConfiguration, PluginBundle,
+        PluginBundleError, PluginInstance,
     },
-    process::StartedPluginAudioProcessor,
     utils::Cookie,
 };
 
+use crate::{
+    audio::MAX_PLUGINS,
+    audio_io::AudioIO,
+    plugin_processor::{
+        to_param_output, NoteInput, ParamChange, ParamOutputKind, PluginAudioProcessor, PluginId,
+    },
+};
+
+/// Requests a plugin can make from any thread. They're only flagged here and
+/// handled on the main thread by `PluginsContainer::sync`.
 #[derive(Default)]
-pub struct PluginHostShared;
+pub struct PluginRequests {
+    restart: AtomicBool,
+    process: AtomicBool,
+    callback: AtomicBool,
+    flush: AtomicBool,
+}
+
+#[derive(Clone, Copy)]
+pub struct PendingRequests {
+    pub restart: bool,
+    pub process: bool,
+    pub callback: bool,
+    /// The plugin has param changes to report.
+    pub flush: bool,
+}
+
+impl PluginRequests {
+    fn take(&self) -> PendingRequests {
+        PendingRequests {
+            restart: self.restart.swap(false, Ordering::AcqRel),
+            process: self.process.swap(false, Ordering::AcqRel),
+            callback: self.callback.swap(false, Ordering::AcqRel),
+            flush: self.flush.swap(false, Ordering::AcqRel),
+        }
+    }
+}
+
+pub struct PluginHostShared {
+    requests: Arc<PluginRequests>,
+}
 
 impl<'a> HostShared<'a> for PluginHostShared {
     fn request_restart(&self) {
-        todo!()
+        self.requests.restart.store(true, Ordering::Release);
     }
 
     fn request_process(&self) {
-        todo!()
+        self.requests.process.store(true, Ordering::Release);
     }
 
     fn request_callback(&self) {
-        todo!()
+        self.requests.callback.store(true, Ordering::Release);
+    }
+}
+
+impl HostParamsImplShared for PluginHostShared {
+    fn request_flush(&self) {
+        self.requests.flush.store(true, Ordering::Release);
+    }
+}
+
+/// Main thread callbacks arrive while the plugin is being called, so they're
+/// recorded here and handled by `PluginHost` once the call returns.
+#[derive(Default)]
+pub struct PluginHostMainThread {
+    params_rescan: Option<ParamRescanFlags>,
+    cleared_params: Vec<(u32, ParamClearFlags)>,
+}
+
+impl<'a> HostMainThread<'a> for PluginHostMainThread {}
+
+impl HostParamsImplMainThread for PluginHostMainThread {
+    fn rescan(&mut self, flags: ParamRescanFlags) {
+        self.params_rescan = Some(self.params_rescan.map_or(flags, |pending| pending | flags));
+    }
+
+    fn clear(&mut self, param_id: u32, flags: ParamClearFlags) {
+        self.cleared_params.push((param_id, flags));
     }
 }
 
@@ -41,113 +120,325 @@ impl<'a> HostLogImpl for PluginHostShared {
 }
 
 pub struct PluginHost {
+    pub id: PluginId,
     plugin_instance: PluginInstance<PluginHost>,
     pub name: String,
     pub params: Vec<MyParamInfoData>,
-    audio_processor: Option<StartedPluginAudioProcessor<PluginHost>>,
+    pub input_ports: Vec<MyAudioPortInfo>,
+    pub output_ports: Vec<MyAudioPortInfo>,
+    pub note_ports: Vec<MyNotePortInfo>,
+    pub port_configs: Vec<MyAudioPortsConfig>,
+    selected_port_config: Option<u32>,
+    /// Selected while the plugin was active, applied on the next activation.
+    pending_port_config: Option<u32>,
+    audio_processor: Option<Box<PluginAudioProcessor>>,
+    is_activated: bool,
+    requests: Arc<PluginRequests>,
+    /// Incremented by the current processor on the audio thread.
+    failed_processes: Arc<AtomicU32>,
+    supports_f64: bool,
+    use_f64: bool,
+    /// Set once the user turns 64-bit processing on or off, it then wins
+    /// over what the plugin prefers.
+    chosen_f64: Option<bool>,
 }
 
 impl Host for PluginHost {
     type Shared<'a> = PluginHostShared;
 
-    type MainThread<'a> = ();
+    type MainThread<'a> = PluginHostMainThread;
 
     type AudioProcessor<'a> = ();
 
     fn declare_extensions(builder: &mut HostExtensions<'_, Self>, _shared: &Self::Shared<'_>) {
-        builder.register::<HostLog>();
+        builder.register::<HostLog>().register::<HostParams>();
     }
 }
 
 impl PluginHost {
-    pub fn new(host_info: &HostInfo, path: &str) -> Self {
-        let bundle = PluginBundle::load(path).unwrap();
-        let plugin_factory = bundle.get_plugin_factory().unwrap();
-        let plugin_descriptor = plugin_factory.plugin_descriptor(0).unwrap();
+    pub fn new(
+        id: PluginId,
+        host_info: &HostInfo,
+        path: &str,
+        plugin_id: &str,
+    ) -> Result<Self, PluginLoadError> {
+        let bundle = PluginBundle::load(path).map_err(PluginLoadError::Bundle)?;
+        let plugin_factory = bundle
+            .get_plugin_factory()
+            .ok_or(PluginLoadError::NoFactory)?;
+        let plugin_descriptor = (0..plugin_factory.plugin_count())
+            .filter_map(|index| plugin_factory.plugin_descriptor(index))
+            .find(|descriptor| {
+                descriptor.id().map_or(false, |descriptor_id| {
+                    descriptor_id.to_bytes() == plugin_id.as_bytes()
+                })
+            })
+            .ok_or_else(|| PluginLoadError::PluginNotFound(plugin_id.to_owned()))?;
+        let requests = Arc::new(PluginRequests::default());
+        let shared_requests = requests.clone();
         let plugin_instance = PluginInstance::<PluginHost>::new(
-            |_| PluginHostShared,
-            |_| (),
+            move |_| PluginHostShared {
+                requests: shared_requests,
+            },
+            |_| PluginHostMainThread::default(),
             &bundle,
-            plugin_descriptor.id().unwrap(),
+            plugin_descriptor
+                .id()
+                .ok_or_else(|| PluginLoadError::PluginNotFound(plugin_id.to_owned()))?,
             &host_info,
         )
-        .unwrap();
+        .map_err(PluginLoadError::Instance)?;
 
-        let plugin_params = plugin_instance
-            .shared_plugin_data()
-            .get_extension::<PluginParams>()
-            .unwrap();
-
-        let main_handle = plugin_instance.main_thread_plugin_data();
-        let count = plugin_params.count(&main_handle);
-
-        let mut param_index = 0;
-        let mut params = vec![];
-        while param_index < count {
-            let mut pass_info = MaybeUninit::<ParamInfo>::uninit();
-            let info: ParamInfoData<'_> = plugin_params
-                .get_info(&main_handle, param_index, &mut pass_info)
-                .unwrap()
-                .try_into()
-                .unwrap();
+        let params = read_params(&plugin_instance)?;
 
-            params.push(info.into());
+        let port_configs = audio_ports_configs(&plugin_instance);
 
-            param_index += 1;
-        }
-
-        Self {
+        let mut plugin_host = Self {
+            id,
             plugin_instance,
             name: plugin_descriptor
                 .name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned(),
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| plugin_id.to_owned()),
             params,
+            input_ports: vec![],
+            output_ports: vec![],
+            note_ports: input_note_ports(&plugin_instance),
+            port_configs,
+            selected_port_config: None,
+            pending_port_config: None,
             audio_processor: None,
-        }
+            is_activated: false,
+            requests,
+            failed_processes: Arc::default(),
+            supports_f64: false,
+            use_f64: false,
+            chosen_f64: None,
+        };
+        plugin_host.read_audio_ports();
+
+        Ok(plugin_host)
     }
 
-    pub fn activate(&mut self, audio_configuration: PluginAudioConfiguration) {
-        if self.audio_processor.is_some() {
-            return;
+    /// Re-reads the audio ports, which change with the port configuration.
+    /// 64-bit processing follows the user's choice if it's still supported,
+    /// and what the plugin prefers until there's a choice.
+    fn read_audio_ports(&mut self) {
+        let (input_ports, output_ports) = audio_ports(&self.plugin_instance);
+        let all_ports: Vec<_> = input_ports.iter().chain(&output_ports).collect();
+        let prefers_f64 = all_ports
+            .iter()
+            .any(|port| port.flags.contains(AudioPortFlags::PREFERS_64BITS));
+
+        self.supports_f64 = !all_ports.is_empty()
+            && all_ports
+                .iter()
+                .all(|port| port.flags.contains(AudioPortFlags::SUPPORTS_64BITS));
+        self.use_f64 = self.supports_f64 && self.chosen_f64.unwrap_or(prefers_f64);
+        self.input_ports = input_ports;
+        self.output_ports = output_ports;
+    }
+
+    /// The processor is left stopped, the audio thread starts it once it's
+    /// added there.
+    pub fn activate(
+        &mut self,
+        audio_configuration: PluginAudioConfiguration,
+    ) -> Result<(), PluginLoadError> {
+        if self.is_activated {
+            return Ok(());
         }
 
-        let audio_processor = self
+        let max_frames = *audio_configuration.frames_count_range.end() as usize;
+        let processor = self
             .plugin_instance
             .activate(|_, _, _| (), audio_configuration)
-            .unwrap();
-
-        // Let's send the audio processor to a dedicated audio processing thread.
-        let audio_processor = std::thread::scope(|s| {
-            s.spawn(|| {
-                let audio_processor = audio_processor.start_processing().unwrap();
-                // TODO: prepare buffers here
-                audio_processor
-            })
-            .join()
-            .unwrap()
-        });
+            .map_err(PluginLoadError::Activation)?;
 
-        self.audio_processor = Some(audio_processor);
+        let processor = PluginAudioProcessor::new(
+            self.id,
+            processor,
+            max_frames,
+            self.use_f64,
+            &self.input_ports,
+            &self.output_ports,
+            self.note_input(),
+        );
+
+        self.failed_processes = processor.failed_processes();
+        self.audio_processor = Some(Box::new(processor));
+        self.is_activated = true;
+
+        Ok(())
     }
 
+    /// Deactivates the plugin. Does nothing while its processor is owned by
+    /// the audio thread, so it has to be taken back first.
     pub fn deactivate(&mut self) {
-        if self.audio_processor.is_none() {
+        let Some(processor) = self.audio_processor.take() else {
             return;
+        };
+
+        self.plugin_instance.deactivate(processor.into_processor());
+        self.is_activated = false;
+    }
+
+    /// Whether the processor is currently owned by the audio thread.
+    pub fn is_processing(&self) -> bool {
+        self.is_activated && self.audio_processor.is_none()
+    }
+
+    pub fn take_processor(&mut self) -> Option<Box<PluginAudioProcessor>> {
+        self.audio_processor.take()
+    }
+
+    pub fn return_processor(&mut self, processor: Box<PluginAudioProcessor>) {
+        self.audio_processor = Some(processor);
+    }
+
+    /// Whether every audio port of the plugin can process 64-bit samples.
+    pub fn supports_f64(&self) -> bool {
+        self.supports_f64
+    }
+
+    pub fn uses_f64(&self) -> bool {
+        self.use_f64
+    }
+
+    /// The buffers are set up on activation, so this restarts the plugin.
+    pub fn set_use_f64(&mut self, use_f64: bool) {
+        if !self.supports_f64 || self.use_f64 == use_f64 {
+            return;
+        }
+
+        self.use_f64 = use_f64;
+        self.chosen_f64 = Some(use_f64);
+        self.requests.restart.store(true, Ordering::Release);
+    }
+
+    /// The dialect notes are sent to the first note input in, if the plugin
+    /// takes notes at all.
+    pub fn note_dialect(&self) -> Option<NoteDialect> {
+        let port = self.note_ports.first()?;
+
+        match port.preferred_dialect {
+            Some(dialect) if port.supported_dialects.supports(dialect) => Some(dialect),
+            _ => [
+                NoteDialect::Clap,
+                NoteDialect::Midi,
+                NoteDialect::MidiMpe,
+                NoteDialect::Midi2,
+            ]
+            .into_iter()
+            .find(|dialect| port.supported_dialects.supports(*dialect)),
+        }
+    }
+
+    fn note_input(&self) -> Option<NoteInput> {
+        let dialect = self.note_dialect()?;
+
+        Some(NoteInput {
+            dialect,
+            supports_midi: self.note_ports[0]
+                .supported_dialects
+                .supports(NoteDialect::Midi),
+        })
+    }
+
+    pub fn selected_port_config(&self) -> Option<u32> {
+        self.selected_port_config
+    }
+
+    /// A configuration can only be selected while the plugin is inactive,
+    /// so this restarts the plugin.
+    pub fn select_port_config(&mut self, config_id: u32) -> Result<(), PluginLoadError> {
+        if self.selected_port_config == Some(config_id) {
+            return Ok(());
         }
 
-        let processor = self.audio_processor.take().unwrap();
-        let processor = processor.stop_processing();
-        self.plugin_instance.deactivate(processor);
+        if self.is_activated {
+            self.pending_port_config = Some(config_id);
+            self.requests.restart.store(true, Ordering::Release);
+            Ok(())
+        } else {
+            self.select_port_config_now(config_id)
+        }
+    }
+
+    /// Has to be called while the plugin is deactivated for a restart.
+    pub fn select_pending_port_config(&mut self) -> Result<(), PluginLoadError> {
+        match self.pending_port_config.take() {
+            Some(config_id) => self.select_port_config_now(config_id),
+            None => Ok(()),
+        }
+    }
+
+    fn select_port_config_now(&mut self, config_id: u32) -> Result<(), PluginLoadError> {
+        let Some(audio_ports_config) = self
+            .plugin_instance
+            .shared_plugin_data()
+            .get_extension::<PluginAudioPortsConfig>()
+        else {
+            return Ok(());
+        };
+
+        let mut main_handle = self.plugin_instance.main_thread_plugin_data();
+        audio_ports_config
+            .select(&mut main_handle, config_id)
+            .map_err(PluginLoadError::PortConfig)?;
+
+        self.selected_port_config = Some(config_id);
+        self.read_audio_ports();
+        Ok(())
+    }
+
+    pub fn take_requests(&self) -> PendingRequests {
+        self.requests.take()
+    }
+
+    /// How many process calls failed since the last call.
+    pub fn take_failed_processes(&self) -> u32 {
+        self.failed_processes.swap(0, Ordering::Relaxed)
+    }
+
+    pub fn on_main_thread(&mut self) {
+        self.plugin_instance.call_on_main_thread_callback();
     }
 
     pub fn name(&self) -> &str {
         &self.name
     }
 
-    pub fn set_value(&mut self, param_id: u32, value: f64) {
+    /// Flushes the change right away if the plugin is deactivated, which is
+    /// the only time the main thread may. Otherwise it goes with the
+    /// processor: queued in it while it's parked, sent to the audio thread
+    /// while it's processing.
+    pub fn set_value(&mut self, param_id: u32, value: f64, audio_io: &mut AudioIO) {
+        if !self.is_activated {
+            self.flush_value(param_id, value);
+            return;
+        }
+
+        let Some(param) = self.params.iter_mut().find(|param| param.id == param_id) else {
+            return;
+        };
+
+        let change = ParamChange {
+            plugin_id: self.id,
+            param_id,
+            cookie: param.cookie.clone(),
+            value,
+        };
+        let is_sent = match &mut self.audio_processor {
+            Some(processor) => processor.push_param(change),
+            None => audio_io.send_param(change),
+        };
+        if is_sent {
+            param.value = value;
+        }
+    }
+
+    fn flush_value(&mut self, param_id: u32, value: f64) {
         let event = ParamValueEvent::new(
             EventHeader::new(0),
             Cookie::empty(),
@@ -159,28 +450,286 @@ impl PluginHost {
             value,
         );
         let input_buffer: [ParamValueEvent; 1] = [event];
-        let input_events = InputEvents::from_buffer(&input_buffer);
+        self.flush(&InputEvents::from_buffer(&input_buffer));
+
+        if let Some(value) = self.param_value(param_id) {
+            self.apply_param_output(param_id, ParamOutputKind::Value(value));
+        }
+    }
+
+    /// Lets the plugin report its param changes when it asked for a flush.
+    /// Only a deactivated plugin is flushed here, an active one reports them
+    /// from its next process call.
+    pub fn flush_params(&mut self, audio_io: &mut AudioIO) {
+        if !self.is_activated {
+            self.flush(&InputEvents::from_buffer(&EventBuffer::new()));
+            return;
+        }
+
+        match &mut self.audio_processor {
+            Some(processor) => processor.wake(),
+            None => audio_io.wake_plugin(self.id),
+        }
+    }
+
+    /// Only allowed while the plugin is deactivated.
+    fn flush(&mut self, input_events: &InputEvents<'_>) {
         let mut buffer = EventBuffer::new();
         let mut output_events = OutputEvents::from_buffer(&mut buffer);
 
         let mut main_handle = self.plugin_instance.main_thread_plugin_data();
-        let plugin_params = self
+        let Some(plugin_params) = self
             .plugin_instance
             .shared_plugin_data()
             .get_extension::<PluginParams>()
-            .unwrap();
+        else {
+            return;
+        };
+
+        plugin_params.flush(&mut main_handle, input_events, &mut output_events);
+
+        for event in buffer.iter() {
+            if let Some((param_id, kind)) = to_param_output(event) {
+                self.apply_param_output(param_id, kind);
+            }
+        }
+    }
+
+    fn param_value(&self, param_id: u32) -> Option<f64> {
+        let mut main_handle = self.plugin_instance.main_thread_plugin_data();
+        let plugin_params = self
+            .plugin_instance
+            .shared_plugin_data()
+            .get_extension::<PluginParams>()?;
+
+        plugin_params.get_value::<PluginHost>(&mut main_handle, param_id)
+    }
+
+    /// Handles the rescans and clears the plugin asked for since the last
+    /// call. Has to be called regularly from the UI loop.
+    pub fn handle_params_changes(&mut self, audio_io: &mut AudioIO) {
+        let main_thread = self.plugin_instance.main_thread_host_data_mut();
+        let rescan = main_thread.params_rescan.take();
+        let cleared_params = std::mem::take(&mut main_thread.cleared_params);
+
+        for (param_id, flags) in cleared_params {
+            self.clear_param(param_id, flags, audio_io);
+        }
+        if let Some(flags) = rescan {
+            self.rescan_params(flags);
+        }
+    }
+
+    /// Re-reads the params like `new` does, keeping the values of the ones
+    /// that were already there unless the plugin says values changed.
+    fn rescan_params(&mut self, flags: ParamRescanFlags) {
+        if flags.intersects(ParamRescanFlags::ALL | ParamRescanFlags::INFO) {
+            match read_params(&self.plugin_instance) {
+                Ok(mut params) => {
+                    for param in &mut params {
+                        match self.params.iter().find(|old| old.id == param.id) {
+                            Some(old) => {
+                                param.value = old.value;
+                                param.is_adjusted = old.is_adjusted;
+                            }
+                            None => {
+                                if let Some(value) = self.param_value(param.id) {
+                                    param.value = value;
+                                }
+                            }
+                        }
+                    }
+                    self.params = params;
+                }
+                Err(err) => println!("PARAMS RESCAN ERROR: {err}"),
+            }
+        }
+
+        if flags.intersects(ParamRescanFlags::ALL | ParamRescanFlags::VALUES) {
+            for index in 0..self.params.len() {
+                if let Some(value) = self.param_value(self.params[index].id) {
+                    self.params[index].value = value;
+                }
+            }
+        }
+    }
+
+    /// Drops what still refers to the param. It's only removed from
+    /// `params` by the rescan the plugin follows up with.
+    fn clear_param(&mut self, param_id: u32, flags: ParamClearFlags, audio_io: &mut AudioIO) {
+        // Param changes are the only automation, and there's no modulation
+        if !flags.intersects(ParamClearFlags::ALL | ParamClearFlags::AUTOMATIONS) {
+            return;
+        }
 
-        plugin_params.flush(&mut main_handle, &input_events, &mut output_events);
+        match &mut self.audio_processor {
+            Some(processor) => processor.clear_param(param_id),
+            None if self.is_activated => audio_io.clear_param(self.id, param_id),
+            None => {}
+        }
 
-        if let Some(value) = plugin_params.get_value::<PluginHost>(&mut main_handle, param_id) {
-            self.params
-                .iter_mut()
-                .find(|param| param.id == param_id)
-                .map(|param| param.value = value);
+        // No gesture end is coming for a param that's gone
+        if flags.contains(ParamClearFlags::ALL) {
+            if let Some(param) = self.params.iter_mut().find(|param| param.id == param_id) {
+                param.is_adjusted = false;
+            }
+        }
+    }
+
+    /// Follows a change the plugin made itself, e.g. from its own GUI.
+    pub fn apply_param_output(&mut self, param_id: u32, kind: ParamOutputKind) {
+        let Some(param) = self.params.iter_mut().find(|param| param.id == param_id) else {
+            return;
         };
+
+        match kind {
+            ParamOutputKind::Value(value) => param.value = value,
+            ParamOutputKind::GestureBegin => param.is_adjusted = true,
+            ParamOutputKind::GestureEnd => param.is_adjusted = false,
+        }
+    }
+}
+
+/// Every param of the plugin, at its default value. Plugins without
+/// parameters don't have to implement the extension.
+fn read_params(
+    plugin_instance: &PluginInstance<PluginHost>,
+) -> Result<Vec<MyParamInfoData>, PluginLoadError> {
+    let mut params = vec![];
+
+    let Some(plugin_params) = plugin_instance
+        .shared_plugin_data()
+        .get_extension::<PluginParams>()
+    else {
+        return Ok(params);
+    };
+
+    let main_handle = plugin_instance.main_thread_plugin_data();
+    let count = plugin_params.count(&main_handle);
+
+    let mut param_index = 0;
+    while param_index < count {
+        let mut pass_info = MaybeUninit::<ParamInfo>::uninit();
+        let info: ParamInfoData<'_> = plugin_params
+            .get_info(&main_handle, param_index, &mut pass_info)
+            .and_then(|info| info.try_into().ok())
+            .ok_or(PluginLoadError::InvalidParamInfo(param_index))?;
+
+        params.push(info.into());
+
+        param_index += 1;
     }
+
+    Ok(params)
+}
+
+/// The input and output ports of the plugin. A plugin that doesn't
+/// implement the extension has no audio ports at all.
+fn audio_ports(
+    plugin_instance: &PluginInstance<PluginHost>,
+) -> (Vec<MyAudioPortInfo>, Vec<MyAudioPortInfo>) {
+    let Some(audio_ports) = plugin_instance
+        .shared_plugin_data()
+        .get_extension::<PluginAudioPorts>()
+    else {
+        return (vec![], vec![]);
+    };
+
+    let main_handle = plugin_instance.main_thread_plugin_data();
+    let mut buffer = AudioPortInfoBuffer::new();
+    let mut ports = |is_input| {
+        let mut ports = vec![];
+        for index in 0..audio_ports.count(&main_handle, is_input) {
+            if let Some(info) = audio_ports.get(&main_handle, index, is_input, &mut buffer) {
+                ports.push(MyAudioPortInfo::from(info));
+            }
+        }
+        ports
+    };
+
+    (ports(true), ports(false))
 }
 
+fn input_note_ports(plugin_instance: &PluginInstance<PluginHost>) -> Vec<MyNotePortInfo> {
+    let Some(note_ports) = plugin_instance
+        .shared_plugin_data()
+        .get_extension::<PluginNotePorts>()
+    else {
+        return vec![];
+    };
+
+    let main_handle = plugin_instance.main_thread_plugin_data();
+    let mut buffer = NotePortInfoBuffer::new();
+    let mut ports = vec![];
+
+    for index in 0..note_ports.count(&main_handle, true) {
+        if let Some(info) = note_ports.get(&main_handle, index, true, &mut buffer) {
+            ports.push(info.into());
+        }
+    }
+
+    ports
+}
+
+/// The alternative port layouts the plugin offers.
+fn audio_ports_configs(plugin_instance: &PluginInstance<PluginHost>) -> Vec<MyAudioPortsConfig> {
+    let Some(audio_ports_config) = plugin_instance
+        .shared_plugin_data()
+        .get_extension::<PluginAudioPortsConfig>()
+    else {
+        return vec![];
+    };
+
+    let mut main_handle = plugin_instance.main_thread_plugin_data();
+    let mut buffer = AudioPortsConfigBuffer::new();
+    let mut configs = vec![];
+
+    for index in 0..audio_ports_config.count(&mut main_handle) {
+        if let Some(config) = audio_ports_config.get(&mut main_handle, index, &mut buffer) {
+            configs.push(config.into());
+        }
+    }
+
+    configs
+}
+
+#[derive(Debug)]
+pub enum PluginLoadError {
+    Bundle(PluginBundleError),
+    NoFactory,
+    PluginNotFound(String),
+    Instance(HostError),
+    InvalidParamInfo(u32),
+    Activation(HostError),
+    PortConfig(HostError),
+    StartProcessing,
+    TooManyPlugins,
+}
+
+impl Display for PluginLoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bundle(err) => write!(f, "Failed to load the bundle: {err:?}"),
+            Self::NoFactory => write!(f, "The bundle has no plugin factory"),
+            Self::PluginNotFound(id) => write!(f, "The bundle has no plugin with id {id}"),
+            Self::Instance(err) => write!(f, "Failed to instantiate the plugin: {err:?}"),
+            Self::InvalidParamInfo(index) => {
+                write!(f, "The plugin returned invalid info for parameter #{index}")
+            }
+            Self::Activation(err) => write!(f, "Failed to activate the plugin: {err:?}"),
+            Self::PortConfig(err) => {
+                write!(f, "Failed to select the audio ports configuration: {err:?}")
+            }
+            Self::StartProcessing => write!(f, "The plugin failed to start processing"),
+            Self::TooManyPlugins => {
+                write!(f, "No more than {MAX_PLUGINS} plugins can be loaded")
+            }
+        }
+    }
+}
+
+impl Error for PluginLoadError {}
+
 pub struct MyParamInfoData {
     pub id: u32,
     pub flags: ParamInfoFlags,
@@ -190,6 +739,8 @@ pub struct MyParamInfoData {
     pub min_value: f64,
     pub max_value: f64,
     pub value: f64,
+    /// Being changed from the plugin's GUI.
+    pub is_adjusted: bool,
 }
 
 impl From<ParamInfoData<'_>> for MyParamInfoData {
@@ -203,6 +754,106 @@ impl From<ParamInfoData<'_>> for MyParamInfoData {
             min_value: info.min_value,
             max_value: info.max_value,
             value: info.default_value,
+            is_adjusted: false,
+        }
+    }
+}
+
+pub struct MyAudioPortInfo {
+    pub id: u32,
+    pub name: String,
+    pub channel_count: u32,
+    pub flags: AudioPortFlags,
+    pub port_type: Option<String>,
+    pub in_place_pair: Option<u32>,
+}
+
+impl MyAudioPortInfo {
+    pub fn is_main(&self) -> bool {
+        self.flags.contains(AudioPortFlags::IS_MAIN)
+    }
+}
+
+impl From<AudioPortInfoData<'_>> for MyAudioPortInfo {
+    fn from(info: AudioPortInfoData<'_>) -> Self {
+        MyAudioPortInfo {
+            id: info.id,
+            name: String::from_utf8_lossy(info.name).into_owned(),
+            channel_count: info.channel_count,
+            flags: info.flags,
+            port_type: info
+                .port_type
+                .map(|port_type| port_type.0.to_string_lossy().into_owned()),
+            in_place_pair: info.in_place_pair,
         }
     }
 }
+
+impl Display for MyAudioPortInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} ch", self.name, self.channel_count)?;
+        if let Some(port_type) = &self.port_type {
+            write!(f, ", {port_type}")?;
+        }
+        if self.is_main() {
+            write!(f, ", main")?;
+        }
+        if let Some(pair) = self.in_place_pair {
+            write!(f, ", in place with #{pair}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+pub struct MyAudioPortsConfig {
+    pub id: u32,
+    pub name: String,
+    pub input_port_count: u32,
+    pub output_port_count: u32,
+}
+
+impl From<AudioPortsConfiguration<'_>> for MyAudioPortsConfig {
+    fn from(config: AudioPortsConfiguration<'_>) -> Self {
+        MyAudioPortsConfig {
+            id: config.id,
+            name: String::from_utf8_lossy(config.name).into_owned(),
+            input_port_count: config.input_port_count,
+            output_port_count: config.output_port_count,
+        }
+    }
+}
+
+pub struct MyNotePortInfo {
+    pub id: u32,
+    pub name: String,
+    pub supported_dialects: NoteDialects,
+    pub preferred_dialect: Option<NoteDialect>,
+}
+
+impl From<NotePortInfoData<'_>> for MyNotePortInfo {
+    fn from(info: NotePortInfoData<'_>) -> Self {
+        MyNotePortInfo {
+            id: info.id,
+            name: String::from_utf8_lossy(info.name).into_owned(),
+            supported_dialects: info.supported_dialects,
+            preferred_dialect: info.preferred_dialect,
+        }
+    }
+}
+
+impl Display for MyNotePortInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let dialects: Vec<_> = [
+            (NoteDialect::Clap, "CLAP"),
+            (NoteDialect::Midi, "MIDI"),
+            (NoteDialect::MidiMpe, "MIDI-MPE"),
+            (NoteDialect::Midi2, "MIDI2"),
+        ]
+        .into_iter()
+        .filter(|(dialect, _)| self.supported_dialects.supports(*dialect))
+        .map(|(_, name)| name)
+        .collect();
+
+        write!(f, "{} ({})", self.name, dialects.join(", "))
+    }
+}
@@ -0,0 +1,714 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use clack_extensions::note_ports::NoteDialect;
+use clack_host::{
+    events::{
+        event_types::{
+            Midi2Event, MidiEvent, NoteChokeEvent, NoteEvent, NoteOffEvent, NoteOnEvent,
+            ParamGestureBeginEvent, ParamGestureEndEvent, ParamValueEvent, TransportEvent,
+        },
+        UnknownEvent,
+    },
+    prelude::{
+        AudioPortBuffer, AudioPortBufferType, AudioPorts, EventBuffer, EventHeader, InputChannel,
+        InputEvents, OutputEvents,
+    },
+    process::{ProcessStatus, StartedPluginAudioProcessor, StoppedPluginAudioProcessor},
+    utils::Cookie,
+};
+
+use crate::{
+    notes::{Note, NoteKind},
+    plugin_host::{MyAudioPortInfo, PluginHost},
+};
+
+pub type PluginId = usize;
+
+/// How many param changes can wait for the audio thread.
+pub const PARAM_QUEUE_CAPACITY: usize = 256;
+
+/// A param change from the UI, delivered in the plugin's next process call.
+pub struct ParamChange {
+    pub plugin_id: PluginId,
+    pub param_id: u32,
+    pub cookie: Cookie,
+    pub value: f64,
+}
+
+/// What goes through the param queue to the audio thread.
+pub enum ParamMessage {
+    Change(ParamChange),
+    /// Drops the changes to the param still waiting for the plugin.
+    Clear {
+        plugin_id: PluginId,
+        param_id: u32,
+    },
+}
+
+#[derive(Clone, Copy)]
+pub enum ParamOutputKind {
+    Value(f64),
+    /// The user started or stopped adjusting the param in the plugin's GUI.
+    GestureBegin,
+    GestureEnd,
+}
+
+/// A param change coming from the plugin itself.
+#[derive(Clone, Copy)]
+pub struct ParamOutput {
+    pub plugin_id: PluginId,
+    pub param_id: u32,
+    pub kind: ParamOutputKind,
+}
+
+/// Pushes past this are refused instead of growing the buffer.
+const MAX_INPUT_EVENTS: usize = 128;
+const MAX_OUTPUT_EVENTS: usize = 128;
+
+/// How notes reach the plugin's first note port.
+#[derive(Clone, Copy)]
+pub struct NoteInput {
+    pub dialect: NoteDialect,
+    /// Other MIDI messages can be sent as is, whatever the dialect of notes.
+    pub supports_midi: bool,
+}
+
+/// An input event waiting for the next process call.
+enum QueuedEvent {
+    Note(Note),
+    Midi([u8; 3]),
+    Param(ParamChange),
+}
+
+/// The channels of one audio port.
+struct PortBuffers {
+    channels: Vec<Vec<f32>>,
+    /// Only allocated for plugins processing in 64-bit.
+    channels_f64: Vec<Vec<f64>>,
+}
+
+impl PortBuffers {
+    fn new(port: &MyAudioPortInfo, max_frames: usize, use_f64: bool) -> Self {
+        let channel_count = port.channel_count as usize;
+        let f64_channel_count = if use_f64 { channel_count } else { 0 };
+
+        Self {
+            channels: vec![vec![0.0; max_frames]; channel_count],
+            channels_f64: vec![vec![0.0; max_frames]; f64_channel_count],
+        }
+    }
+}
+
+/// Processing is started and stopped on the audio thread, so the processor
+/// switches between the two there.
+enum ProcessorState {
+    Stopped(StoppedPluginAudioProcessor<PluginHost>),
+    Started(StartedPluginAudioProcessor<PluginHost>),
+    /// Only seen while switching.
+    Switching,
+}
+
+/// The audio thread side of a loaded plugin: the processor together with the
+/// buffers of each of its audio ports.
+pub struct PluginAudioProcessor {
+    pub id: PluginId,
+    processor: ProcessorState,
+    input_ports: AudioPorts,
+    output_ports: AudioPorts,
+    input_buffers: Vec<PortBuffers>,
+    output_buffers: Vec<PortBuffers>,
+    /// The ports connected to the routing, other ones get silence.
+    main_input: Option<usize>,
+    main_output: Option<usize>,
+    is_f64: bool,
+    /// `None` for plugins that don't take notes.
+    note_input: Option<NoteInput>,
+    /// What the host sends for the next process call, by time.
+    queued: Vec<(u32, QueuedEvent)>,
+    /// What other plugins played for this one, by time.
+    forwarded: Vec<(u32, [u8; 3])>,
+    input_events: EventBuffer,
+    output_events: EventBuffer,
+    /// The notes and MIDI the plugin sent during the last block, with their
+    /// frame offset.
+    output_midi: Vec<(u32, [u8; 3])>,
+    /// The param changes the plugin reported during the last block.
+    output_params: Vec<ParamOutput>,
+    max_frames: usize,
+    steady_time: u64,
+    is_sleeping: bool,
+    /// Counted here and reported by the main thread, printing would block.
+    failed_processes: Arc<AtomicU32>,
+}
+
+impl PluginAudioProcessor {
+    pub fn new(
+        id: PluginId,
+        processor: StoppedPluginAudioProcessor<PluginHost>,
+        max_frames: usize,
+        use_f64: bool,
+        input_ports: &[MyAudioPortInfo],
+        output_ports: &[MyAudioPortInfo],
+        note_input: Option<NoteInput>,
+    ) -> Self {
+        Self {
+            id,
+            processor: ProcessorState::Stopped(processor),
+            input_ports: AudioPorts::with_capacity(channel_count(input_ports), input_ports.len()),
+            output_ports: AudioPorts::with_capacity(
+                channel_count(output_ports),
+                output_ports.len(),
+            ),
+            input_buffers: input_ports
+                .iter()
+                .map(|port| PortBuffers::new(port, max_frames, use_f64))
+                .collect(),
+            output_buffers: output_ports
+                .iter()
+                .map(|port| PortBuffers::new(port, max_frames, use_f64))
+                .collect(),
+            main_input: main_port(input_ports),
+            main_output: main_port(output_ports),
+            is_f64: use_f64,
+            note_input,
+            queued: Vec::with_capacity(MAX_INPUT_EVENTS),
+            forwarded: Vec::with_capacity(MAX_INPUT_EVENTS),
+            input_events: EventBuffer::with_capacity(MAX_INPUT_EVENTS),
+            output_events: EventBuffer::with_capacity(MAX_OUTPUT_EVENTS),
+            output_midi: Vec::with_capacity(MAX_OUTPUT_EVENTS),
+            output_params: Vec::with_capacity(MAX_OUTPUT_EVENTS),
+            max_frames,
+            steady_time: 0,
+            is_sleeping: false,
+            failed_processes: Arc::default(),
+        }
+    }
+
+    /// A sleeping plugin asked not to be processed until it calls
+    /// `request_process`.
+    pub fn is_sleeping(&self) -> bool {
+        self.is_sleeping
+    }
+
+    /// Shared with the main thread to report from.
+    pub fn failed_processes(&self) -> Arc<AtomicU32> {
+        self.failed_processes.clone()
+    }
+
+    pub fn wake(&mut self) {
+        self.is_sleeping = false;
+    }
+
+    /// Has to be called on the audio thread. Returns `false` if the plugin
+    /// failed to start, it stays stopped then.
+    pub fn start_processing(&mut self) -> bool {
+        self.processor = match std::mem::replace(&mut self.processor, ProcessorState::Switching) {
+            ProcessorState::Stopped(processor) => match processor.start_processing() {
+                Ok(processor) => ProcessorState::Started(processor),
+                Err(err) => ProcessorState::Stopped(err.into_stopped_processor()),
+            },
+            state => state,
+        };
+
+        matches!(self.processor, ProcessorState::Started(_))
+    }
+
+    /// Has to be called on the audio thread before the processor is handed
+    /// back to the main thread.
+    pub fn stop_processing(&mut self) {
+        self.processor = match std::mem::replace(&mut self.processor, ProcessorState::Switching) {
+            ProcessorState::Started(processor) => {
+                ProcessorState::Stopped(processor.stop_processing())
+            }
+            state => state,
+        };
+    }
+
+    /// The processor to deactivate the plugin with. The audio thread stops it
+    /// before handing it back.
+    pub fn into_processor(self) -> StoppedPluginAudioProcessor<PluginHost> {
+        match self.processor {
+            ProcessorState::Stopped(processor) => processor,
+            ProcessorState::Started(processor) => processor.stop_processing(),
+            ProcessorState::Switching => unreachable!("the processor is never left switching"),
+        }
+    }
+
+    /// Queues a note for the next process call, `time` frames into it, on the
+    /// first note port. Returns `false` if there's no room left for it, notes
+    /// are dropped without a word for plugins that don't take any.
+    pub fn push_note(&mut self, time: u32, note: Note) -> bool {
+        if self.note_input.is_none() {
+            return true;
+        }
+        if !self.queue(time, QueuedEvent::Note(note)) {
+            return false;
+        }
+
+        // A sleeping instrument has to wake up to play the note
+        self.is_sleeping = false;
+        true
+    }
+
+    /// Queues a param change at the start of the next process call. Returns
+    /// `false` if there's no room left for it.
+    pub fn push_param(&mut self, change: ParamChange) -> bool {
+        if !self.queue(0, QueuedEvent::Param(change)) {
+            return false;
+        }
+
+        // Changes have to be processed even without audio going through
+        self.is_sleeping = false;
+        true
+    }
+
+    /// Drops the changes to the param waiting for the next process call.
+    pub fn clear_param(&mut self, param_id: u32) {
+        self.queued.retain(|(_, event)| {
+            !matches!(event, QueuedEvent::Param(change) if change.param_id == param_id)
+        });
+    }
+
+    /// Queues a MIDI 1.0 channel message like `push_note`.
+    pub fn push_midi(&mut self, time: u32, message: [u8; 3]) -> bool {
+        if self.note_input.is_none() {
+            return true;
+        }
+        if !self.queue(time, QueuedEvent::Midi(message)) {
+            return false;
+        }
+
+        self.is_sleeping = false;
+        true
+    }
+
+    /// Queues a message another plugin played during the current block. It's
+    /// merged with the rest by time, a block late if this plugin already ran.
+    pub fn forward_midi(&mut self, time: u32, message: [u8; 3]) {
+        if self.note_input.is_none() || self.is_input_full() {
+            return;
+        }
+
+        insert_by_time(&mut self.forwarded, time, message);
+        self.is_sleeping = false;
+    }
+
+    fn queue(&mut self, time: u32, event: QueuedEvent) -> bool {
+        if self.is_input_full() {
+            return false;
+        }
+
+        insert_by_time(&mut self.queued, time, event);
+        true
+    }
+
+    /// Whether pushes would be refused until the next process call.
+    pub fn is_input_full(&self) -> bool {
+        self.queued.len() + self.forwarded.len() >= MAX_INPUT_EVENTS
+    }
+
+    /// What the plugin played during the last `process_in_place` call, as
+    /// MIDI 1.0 messages whatever its dialect.
+    pub fn output_midi(&self) -> &[(u32, [u8; 3])] {
+        &self.output_midi
+    }
+
+    pub fn output_params(&self) -> &[ParamOutput] {
+        &self.output_params
+    }
+
+    /// Fills a non-main input port for the next `process_in_place` call. Ports
+    /// that aren't fed stay silent.
+    pub fn feed_input(&mut self, port: usize, buffers: &[Vec<f32>], frames: usize) {
+        if Some(port) == self.main_input {
+            return;
+        }
+
+        let Some(port) = self.input_buffers.get_mut(port) else {
+            return;
+        };
+
+        for (channel, input) in port.channels.iter_mut().enumerate() {
+            input[..frames].copy_from_slice(&buffers[channel % buffers.len()][..frames]);
+        }
+    }
+
+    /// Feeds the first `frames` samples of `buffers` through the plugin's main
+    /// input and replaces them with its main output. `frames` must not exceed
+    /// `max_frames`.
+    pub fn process_in_place(
+        &mut self,
+        buffers: &mut [Vec<f32>],
+        frames: usize,
+        transport: &TransportEvent,
+    ) {
+        self.output_midi.clear();
+        self.output_params.clear();
+
+        if let Some(main_input) = self.main_input {
+            for (channel, input) in self.input_buffers[main_input]
+                .channels
+                .iter_mut()
+                .enumerate()
+            {
+                input[..frames].copy_from_slice(&buffers[channel % buffers.len()][..frames]);
+            }
+        }
+
+        if self.is_sleeping {
+            let is_input_silent = self
+                .input_buffers
+                .iter()
+                .all(|port| is_silent(&port.channels, frames));
+
+            if is_input_silent {
+                for buffer in buffers.iter_mut() {
+                    buffer[..frames].fill(0.0);
+                }
+                return;
+            }
+
+            self.is_sleeping = false;
+        }
+
+        self.process(frames, transport);
+
+        // Sidechains are fed again for every block
+        for (index, port) in self.input_buffers.iter_mut().enumerate() {
+            if Some(index) != self.main_input {
+                for input in &mut port.channels {
+                    input[..frames].fill(0.0);
+                }
+            }
+        }
+
+        let Some(main_output) = self.main_output else {
+            for buffer in buffers.iter_mut() {
+                buffer[..frames].fill(0.0);
+            }
+            return;
+        };
+
+        let outputs = &self.output_buffers[main_output].channels;
+        for (channel, buffer) in buffers.iter_mut().enumerate() {
+            buffer[..frames].copy_from_slice(&outputs[channel % outputs.len()][..frames]);
+        }
+    }
+
+    fn process(&mut self, frames: usize, transport: &TransportEvent) {
+        debug_assert!(frames <= self.max_frames);
+
+        // Only started processors are added to the audio
+        let ProcessorState::Started(processor) = &mut self.processor else {
+            return;
+        };
+
+        if self.is_f64 {
+            for port in &mut self.input_buffers {
+                for (input, input_f64) in port.channels.iter().zip(&mut port.channels_f64) {
+                    for (sample, sample_f64) in input[..frames].iter().zip(&mut input_f64[..frames])
+                    {
+                        *sample_f64 = *sample as f64;
+                    }
+                }
+            }
+        }
+
+        let inputs = if self.is_f64 {
+            let ports = self.input_buffers.iter_mut().map(|port| AudioPortBuffer {
+                latency: 0,
+                channels: AudioPortBufferType::f64_input_only(
+                    port.channels_f64
+                        .iter_mut()
+                        .map(move |buffer| InputChannel::variable(&mut buffer[..frames])),
+                ),
+            });
+            self.input_ports.with_input_buffers(ports)
+        } else {
+            let ports = self.input_buffers.iter_mut().map(|port| AudioPortBuffer {
+                latency: 0,
+                channels: AudioPortBufferType::f32_input_only(
+                    port.channels
+                        .iter_mut()
+                        .map(move |buffer| InputChannel::variable(&mut buffer[..frames])),
+                ),
+            });
+            self.input_ports.with_input_buffers(ports)
+        };
+
+        let mut outputs = if self.is_f64 {
+            let ports = self.output_buffers.iter_mut().map(|port| AudioPortBuffer {
+                latency: 0,
+                channels: AudioPortBufferType::f64_output_only(
+                    port.channels_f64
+                        .iter_mut()
+                        .map(move |buffer| &mut buffer[..frames]),
+                ),
+            });
+            self.output_ports.with_output_buffers(ports)
+        } else {
+            let ports = self.output_buffers.iter_mut().map(|port| AudioPortBuffer {
+                latency: 0,
+                channels: AudioPortBufferType::f32_output_only(
+                    port.channels
+                        .iter_mut()
+                        .map(move |buffer| &mut buffer[..frames]),
+                ),
+            });
+            self.output_ports.with_output_buffers(ports)
+        };
+
+        fill_input_events(
+            &mut self.input_events,
+            &mut self.queued,
+            &mut self.forwarded,
+            self.note_input,
+            frames,
+        );
+        self.output_events.clear();
+        let input_events = InputEvents::from_buffer(&self.input_events);
+        let mut output_events = OutputEvents::from_buffer(&mut self.output_events);
+
+        match processor.process(
+            &inputs,
+            &mut outputs,
+            &input_events,
+            &mut output_events,
+            Some(self.steady_time),
+            Some(transport),
+        ) {
+            Ok(ProcessStatus::Sleep) => self.is_sleeping = true,
+            Ok(_) => {}
+            Err(_) => {
+                self.failed_processes.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.input_events.clear();
+        self.steady_time += frames as u64;
+
+        // No more than their capacity, so the lists never grow here
+        for event in self.output_events.iter().take(MAX_OUTPUT_EVENTS) {
+            if let Some(message) = to_midi(event) {
+                self.output_midi.push((event.header().time(), message));
+            } else if let Some((param_id, kind)) = to_param_output(event) {
+                self.output_params.push(ParamOutput {
+                    plugin_id: self.id,
+                    param_id,
+                    kind,
+                });
+            }
+        }
+
+        for port in &mut self.output_buffers {
+            for (output, output_f64) in port.channels.iter_mut().zip(&port.channels_f64) {
+                for (sample, sample_f64) in output[..frames].iter_mut().zip(&output_f64[..frames]) {
+                    *sample = *sample_f64 as f32;
+                }
+            }
+        }
+    }
+}
+
+/// Keeps events pushed at the same time in the order they came.
+fn insert_by_time<T>(events: &mut Vec<(u32, T)>, time: u32, event: T) {
+    let index = events.partition_point(|(other, _)| *other <= time);
+    events.insert(index, (time, event));
+}
+
+/// Merges the queued and forwarded events by time into the buffer handed to
+/// the plugin, which has to be sorted. Times past the block are clamped to
+/// its last frame.
+fn fill_input_events(
+    input_events: &mut EventBuffer,
+    queued: &mut Vec<(u32, QueuedEvent)>,
+    forwarded: &mut Vec<(u32, [u8; 3])>,
+    note_input: Option<NoteInput>,
+    frames: usize,
+) {
+    input_events.clear();
+    let last_frame = frames.saturating_sub(1) as u32;
+    let mut queued = queued.drain(..).peekable();
+    let mut forwarded = forwarded.drain(..).peekable();
+
+    loop {
+        let is_forwarded = match (queued.peek(), forwarded.peek()) {
+            (Some((queued_time, _)), Some((forwarded_time, _))) => forwarded_time < queued_time,
+            (Some(_), None) => false,
+            (None, Some(_)) => true,
+            (None, None) => return,
+        };
+
+        if is_forwarded {
+            if let (Some((time, message)), Some(note_input)) = (forwarded.next(), note_input) {
+                push_midi_event(input_events, note_input, time.min(last_frame), message);
+            }
+            continue;
+        }
+
+        let Some((time, event)) = queued.next() else {
+            return;
+        };
+        let header = EventHeader::new(time.min(last_frame));
+
+        match (event, note_input) {
+            (QueuedEvent::Param(change), _) => input_events.push(&ParamValueEvent::new(
+                header,
+                change.cookie,
+                -1,
+                change.param_id,
+                -1,
+                -1,
+                -1,
+                change.value,
+            )),
+            (QueuedEvent::Note(note), Some(note_input)) => {
+                push_note_event(input_events, note_input.dialect, header, note)
+            }
+            (QueuedEvent::Midi(message), Some(note_input)) => {
+                push_midi_event(input_events, note_input, time.min(last_frame), message)
+            }
+            (_, None) => {}
+        }
+    }
+}
+
+fn push_note_event(
+    input_events: &mut EventBuffer,
+    dialect: NoteDialect,
+    header: EventHeader,
+    note: Note,
+) {
+    let (channel, key) = (note.channel as i16, note.key as i16);
+
+    match (dialect, note.kind) {
+        (NoteDialect::Clap, NoteKind::On) => input_events.push(&NoteOnEvent(NoteEvent::new(
+            header,
+            -1,
+            0,
+            key,
+            channel,
+            note.velocity,
+        ))),
+        (NoteDialect::Clap, NoteKind::Off) => input_events.push(&NoteOffEvent(NoteEvent::new(
+            header,
+            -1,
+            0,
+            key,
+            channel,
+            note.velocity,
+        ))),
+        (NoteDialect::Clap, NoteKind::Choke) => input_events.push(&NoteChokeEvent(NoteEvent::new(
+            header, -1, 0, key, channel, 0.0,
+        ))),
+        (NoteDialect::Midi | NoteDialect::MidiMpe, _) => {
+            input_events.push(&MidiEvent::new(header, 0, note.to_midi()))
+        }
+        (NoteDialect::Midi2, _) => input_events.push(&Midi2Event::new(header, 0, note.to_midi2())),
+    }
+}
+
+/// Plugins speaking CLAP notes get the note ons and offs as such, and the
+/// other messages only if their port takes MIDI too.
+fn push_midi_event(
+    input_events: &mut EventBuffer,
+    note_input: NoteInput,
+    time: u32,
+    message: [u8; 3],
+) {
+    let header = EventHeader::new(time);
+
+    match note_input.dialect {
+        NoteDialect::Clap => match Note::from_midi(message) {
+            Some(note) => push_note_event(input_events, NoteDialect::Clap, header, note),
+            None if note_input.supports_midi => {
+                input_events.push(&MidiEvent::new(header, 0, message))
+            }
+            None => {}
+        },
+        NoteDialect::Midi | NoteDialect::MidiMpe => {
+            input_events.push(&MidiEvent::new(header, 0, message))
+        }
+        // MIDI 1.0 channel voice messages have their own packet type
+        NoteDialect::Midi2 => {
+            let word = 0x2 << 28
+                | (message[0] as u32) << 16
+                | (message[1] as u32) << 8
+                | message[2] as u32;
+            input_events.push(&Midi2Event::new(header, 0, [word, 0, 0, 0]))
+        }
+    }
+}
+
+/// Note ons and offs, MIDI and MIDI 1.0 packets of MIDI 2.0 events. Notes
+/// addressed to all keys or channels have no MIDI equivalent.
+fn to_midi(event: &UnknownEvent) -> Option<[u8; 3]> {
+    if let Some(NoteOnEvent(note)) = event.as_event::<NoteOnEvent>() {
+        return note_to_midi(NoteKind::On, note.channel(), note.key(), note.velocity());
+    }
+    if let Some(NoteOffEvent(note)) = event.as_event::<NoteOffEvent>() {
+        return note_to_midi(NoteKind::Off, note.channel(), note.key(), note.velocity());
+    }
+    if let Some(event) = event.as_event::<MidiEvent>() {
+        return Some(event.data());
+    }
+    if let Some(event) = event.as_event::<Midi2Event>() {
+        let word = event.data()[0];
+        if word >> 28 == 0x2 {
+            return Some([
+                (word >> 16) as u8,
+                (word >> 8) as u8 & 0x7F,
+                word as u8 & 0x7F,
+            ]);
+        }
+    }
+
+    None
+}
+
+/// Param values and gestures, from `process` or a params flush.
+pub fn to_param_output(event: &UnknownEvent) -> Option<(u32, ParamOutputKind)> {
+    if let Some(event) = event.as_event::<ParamValueEvent>() {
+        return Some((event.param_id(), ParamOutputKind::Value(event.value())));
+    }
+    if let Some(event) = event.as_event::<ParamGestureBeginEvent>() {
+        return Some((event.param_id(), ParamOutputKind::GestureBegin));
+    }
+    if let Some(event) = event.as_event::<ParamGestureEndEvent>() {
+        return Some((event.param_id(), ParamOutputKind::GestureEnd));
+    }
+
+    None
+}
+
+fn note_to_midi(kind: NoteKind, channel: i16, key: i16, velocity: f64) -> Option<[u8; 3]> {
+    let note = Note {
+        kind,
+        channel: u8::try_from(channel).ok()?,
+        key: u8::try_from(key).ok()?,
+        velocity,
+    };
+
+    Some(note.to_midi())
+}
+
+fn channel_count(ports: &[MyAudioPortInfo]) -> usize {
+    ports.iter().map(|port| port.channel_count as usize).sum()
+}
+
+/// The port flagged as main, if it has any channels.
+fn main_port(ports: &[MyAudioPortInfo]) -> Option<usize> {
+    ports
+        .iter()
+        .position(|port| port.is_main())
+        .filter(|index| ports[*index].channel_count > 0)
+}
+
+fn is_silent(buffers: &[Vec<f32>], frames: usize) -> bool {
+    buffers
+        .iter()
+        .all(|buffer| buffer[..frames].iter().all(|sample| *sample == 0.0))
+}
@@ -1,15 +1,38 @@
+use std::collections::HashMap;
+
 use clack_host::prelude::{HostInfo, PluginAudioConfiguration};
 
-use crate::plugin_host::PluginHost;
+use crate::{
+    audio::MAX_PLUGINS,
+    audio_io::AudioIO,
+    plugin_host::{PluginHost, PluginLoadError},
+    plugin_processor::{ParamOutput, PluginId},
+    routing_graph::{NodeKind, RoutingError, RoutingGraph},
+};
 
 pub struct PluginsContainer {
     host_info: HostInfo,
+    /// The effects chain: each plugin processes the output of the previous one.
     pub plugins: Vec<PluginHost>,
     audio_configuration: PluginAudioConfiguration,
+    next_plugin_id: PluginId,
+    plugins_to_unload: Vec<PluginId>,
+    plugins_to_restart: Vec<PluginId>,
+    graph: RoutingGraph,
+    /// Route audio through `graph` instead of the plain chain of `plugins`.
+    use_graph: bool,
+    /// What feeds the non-main input ports of the plugins in the chain, by
+    /// plugin and port. Kept apart from `graph`, which is the user's own.
+    chain_sidechains: HashMap<(PluginId, usize), NodeKind>,
+    /// Plugins whose notes go to the next plugin in `plugins`.
+    forwarding_notes: Vec<PluginId>,
+    is_routing_changed: bool,
+    /// The last error of a plugin that failed after loading, for the UI.
+    error: Option<PluginLoadError>,
 }
 
 impl PluginsContainer {
-    pub fn init() -> Self {
+    pub fn init(audio_configuration: PluginAudioConfiguration) -> Self {
         Self {
             host_info: HostInfo::new(
                 "Plugins loader",
@@ -19,33 +42,326 @@ impl PluginsContainer {
             )
             .unwrap(),
             plugins: vec![],
-            audio_configuration: PluginAudioConfiguration {
-                sample_rate: 48_000.0,
-                frames_count_range: 32..=32,
-            },
+            audio_configuration,
+            next_plugin_id: 0,
+            plugins_to_unload: vec![],
+            plugins_to_restart: vec![],
+            graph: RoutingGraph::default(),
+            use_graph: false,
+            chain_sidechains: HashMap::new(),
+            forwarding_notes: vec![],
+            is_routing_changed: false,
+            error: None,
         }
     }
 
-    pub fn load(&mut self, path: &str) {
-        let mut plugin_host = PluginHost::new(&self.host_info, path);
-        let audio_configuration = PluginAudioConfiguration {
-            sample_rate: self.audio_configuration.sample_rate,
-            frames_count_range: self.audio_configuration.frames_count_range.clone(),
-        };
-        plugin_host.activate(audio_configuration);
+    pub fn load(&mut self, path: &str, plugin_id: &str) -> Result<(), PluginLoadError> {
+        if self.plugins.len() >= MAX_PLUGINS {
+            return Err(PluginLoadError::TooManyPlugins);
+        }
+
+        let mut plugin_host =
+            PluginHost::new(self.next_plugin_id, &self.host_info, path, plugin_id)?;
+        self.next_plugin_id += 1;
+
+        plugin_host.activate(copy_audio_configuration(&self.audio_configuration))?;
         self.plugins.push(plugin_host);
+        self.is_routing_changed = true;
+
+        Ok(())
+    }
+
+    pub fn move_plugin(&mut self, from: usize, to: usize) {
+        if from >= self.plugins.len() || to >= self.plugins.len() || from == to {
+            return;
+        }
+
+        let plugin_host = self.plugins.remove(from);
+        self.plugins.insert(to, plugin_host);
+        self.is_routing_changed = true;
+    }
+
+    pub fn graph(&self) -> &RoutingGraph {
+        &self.graph
+    }
+
+    pub fn graph_mut(&mut self) -> &mut RoutingGraph {
+        self.is_routing_changed = true;
+        &mut self.graph
+    }
+
+    pub fn uses_graph(&self) -> bool {
+        self.use_graph
+    }
+
+    pub fn set_use_graph(&mut self, use_graph: bool) {
+        self.is_routing_changed |= self.use_graph != use_graph;
+        self.use_graph = use_graph;
+    }
+
+    /// What feeds an input port of a plugin in the chain.
+    pub fn chain_sidechain(&self, plugin_id: PluginId, port: usize) -> Option<NodeKind> {
+        self.chain_sidechains.get(&(plugin_id, port)).copied()
     }
 
-    pub fn unload(&mut self, index: usize) {
+    /// Feeds an input port of a plugin in the chain from the routing input or
+    /// another plugin, or leaves it silent with `None`. It has to fit the
+    /// chain order.
+    pub fn set_chain_sidechain(
+        &mut self,
+        plugin_id: PluginId,
+        port: usize,
+        from: Option<NodeKind>,
+    ) -> Result<(), RoutingError> {
+        let mut chain_graph = self.chain_graph();
+        chain_graph.set_plugin_sidechain(plugin_id, port, from)?;
+
+        match from {
+            Some(from) => self.chain_sidechains.insert((plugin_id, port), from),
+            None => self.chain_sidechains.remove(&(plugin_id, port)),
+        };
+        self.is_routing_changed |= !self.use_graph;
+
+        Ok(())
+    }
+
+    pub fn forwards_notes(&self, plugin_id: PluginId) -> bool {
+        self.forwarding_notes.contains(&plugin_id)
+    }
+
+    /// Lets the next plugin play the notes the given one outputs, e.g. an
+    /// arpeggiator driving a synth.
+    pub fn set_forward_notes(&mut self, plugin_id: PluginId, forward: bool) {
+        self.forwarding_notes.retain(|id| *id != plugin_id);
+        if forward {
+            self.forwarding_notes.push(plugin_id);
+        }
+        self.is_routing_changed = true;
+    }
+
+    /// Re-activates every plugin with the new configuration once its
+    /// processor is back on the main thread.
+    pub fn set_audio_configuration(&mut self, audio_configuration: PluginAudioConfiguration) {
+        if self.audio_configuration.sample_rate == audio_configuration.sample_rate
+            && self.audio_configuration.frames_count_range == audio_configuration.frames_count_range
+        {
+            return;
+        }
+
+        self.audio_configuration = audio_configuration;
+        self.plugins_to_restart = self.plugins.iter().map(|plugin| plugin.id).collect();
+        self.is_routing_changed = true;
+    }
+
+    /// The plan has to be recompiled for a new device channel count.
+    pub fn invalidate_routing(&mut self) {
+        self.is_routing_changed = true;
+    }
+
+    pub fn max_frames(&self) -> usize {
+        *self.audio_configuration.frames_count_range.end() as usize
+    }
+
+    /// Unloads the plugin. If its processor is owned by the audio thread, the
+    /// plugin is only deactivated and dropped by `sync` once it's handed back.
+    pub fn unload(&mut self, index: usize, audio_io: &mut AudioIO) {
         if index >= self.plugins.len() {
             return;
         }
 
+        if self.plugins[index].is_processing() {
+            let id = self.plugins[index].id;
+            audio_io.remove_plugin(id);
+            self.plugins_to_unload.push(id);
+            return;
+        }
+
         let mut plugin_host = self.plugins.remove(index);
         plugin_host.deactivate();
+        self.graph.remove_plugin(plugin_host.id);
+        remove_chain_sidechains(&mut self.chain_sidechains, plugin_host.id);
+        self.is_routing_changed = true;
+    }
+
+    /// Moves plugin processors between the main thread and the audio thread.
+    /// Has to be called regularly from the UI loop.
+    pub fn sync(&mut self, audio_io: &mut AudioIO) {
+        for processor in audio_io.poll_removed_plugins() {
+            if let Some(plugin_host) = self
+                .plugins
+                .iter_mut()
+                .find(|plugin_host| plugin_host.id == processor.id)
+            {
+                plugin_host.return_processor(processor);
+            }
+        }
+
+        for processor in audio_io.poll_rejected_plugins() {
+            if let Some(plugin_host) = self
+                .plugins
+                .iter_mut()
+                .find(|plugin_host| plugin_host.id == processor.id)
+            {
+                // Deactivated so it isn't sent again on the next sync
+                plugin_host.return_processor(processor);
+                plugin_host.deactivate();
+                self.error = Some(PluginLoadError::StartProcessing);
+            }
+        }
+
+        for plugin_host in &mut self.plugins {
+            let requests = plugin_host.take_requests();
+
+            if requests.callback {
+                plugin_host.on_main_thread();
+            }
+
+            let failed_processes = plugin_host.take_failed_processes();
+            if failed_processes > 0 {
+                println!(
+                    "PROCESS ERROR: {} failed {failed_processes} process calls",
+                    plugin_host.name
+                );
+            }
+
+            if requests.flush {
+                plugin_host.flush_params(audio_io);
+            }
+
+            plugin_host.handle_params_changes(audio_io);
+
+            if requests.process && plugin_host.is_processing() {
+                audio_io.wake_plugin(plugin_host.id);
+            }
+
+            if requests.restart && !self.plugins_to_restart.contains(&plugin_host.id) {
+                if plugin_host.is_processing() {
+                    audio_io.remove_plugin(plugin_host.id);
+                }
+                self.plugins_to_restart.push(plugin_host.id);
+            }
+        }
+
+        let plugins_count = self.plugins.len();
+        let plugins_to_unload = &mut self.plugins_to_unload;
+        let graph = &mut self.graph;
+        let chain_sidechains = &mut self.chain_sidechains;
+        self.plugins.retain_mut(|plugin_host| {
+            if plugin_host.is_processing() || !plugins_to_unload.contains(&plugin_host.id) {
+                return true;
+            }
+
+            plugin_host.deactivate();
+            graph.remove_plugin(plugin_host.id);
+            remove_chain_sidechains(chain_sidechains, plugin_host.id);
+            plugins_to_unload.retain(|id| *id != plugin_host.id);
+            false
+        });
+        self.is_routing_changed |= self.plugins.len() != plugins_count;
+
+        for plugin_host in &mut self.plugins {
+            if plugin_host.is_processing() || !self.plugins_to_restart.contains(&plugin_host.id) {
+                continue;
+            }
+
+            plugin_host.deactivate();
+            // Activated anyway, with the previous layout
+            if let Err(err) = plugin_host.select_pending_port_config() {
+                self.error = Some(err);
+            }
+            if let Err(err) =
+                plugin_host.activate(copy_audio_configuration(&self.audio_configuration))
+            {
+                println!("RESTART ERROR: {err}");
+            }
+            self.plugins_to_restart.retain(|id| *id != plugin_host.id);
+        }
+
+        if audio_io.is_activated() {
+            for plugin_host in &mut self.plugins {
+                if let Some(processor) = plugin_host.take_processor() {
+                    audio_io.add_plugin(processor);
+                    // A freshly activated audio starts with an empty chain
+                    self.is_routing_changed = true;
+                }
+            }
+
+            if self.is_routing_changed {
+                let plan = if self.use_graph {
+                    self.graph.compile(audio_io.channels(), self.max_frames())
+                } else {
+                    self.chain_graph()
+                        .compile(audio_io.channels(), self.max_frames())
+                };
+
+                match plan {
+                    Ok(mut plan) => {
+                        plan.set_note_routes(self.note_routes());
+                        audio_io.set_plan(plan);
+                    }
+                    Err(err) => println!("ROUTING ERROR: {err}"),
+                }
+                self.is_routing_changed = false;
+            }
+        }
+    }
+
+    pub fn apply_param_outputs(&mut self, outputs: &[ParamOutput]) {
+        for output in outputs {
+            if let Some(plugin_host) = self
+                .plugins
+                .iter_mut()
+                .find(|plugin_host| plugin_host.id == output.plugin_id)
+            {
+                plugin_host.apply_param_output(output.param_id, output.kind);
+            }
+        }
+    }
+
+    pub fn take_error(&mut self) -> Option<PluginLoadError> {
+        self.error.take()
     }
 
     pub fn is_empty(&self) -> bool {
         self.plugins.is_empty()
     }
+
+    /// The plugins in order, with their sidechains.
+    fn chain_graph(&self) -> RoutingGraph {
+        let chain: Vec<_> = self.plugins.iter().map(|plugin| plugin.id).collect();
+        let mut chain_graph = RoutingGraph::chain(&chain);
+
+        let mut sidechains: Vec<_> = self.chain_sidechains.iter().collect();
+        sidechains.sort_by_key(|(key, _)| **key);
+        for ((plugin_id, port), from) in sidechains {
+            // The ones a reorder turned into a loop stay silent
+            let _ = chain_graph.set_plugin_sidechain(*plugin_id, *port, Some(*from));
+        }
+
+        chain_graph
+    }
+
+    fn note_routes(&self) -> Vec<(PluginId, PluginId)> {
+        self.plugins
+            .windows(2)
+            .filter(|pair| self.forwards_notes(pair[0].id))
+            .map(|pair| (pair[0].id, pair[1].id))
+            .collect()
+    }
+}
+
+/// Forgets the sidechains of an unloaded plugin, and the ones it fed.
+fn remove_chain_sidechains(
+    chain_sidechains: &mut HashMap<(PluginId, usize), NodeKind>,
+    plugin_id: PluginId,
+) {
+    chain_sidechains
+        .retain(|(to, _), from| *to != plugin_id && *from != NodeKind::Plugin(plugin_id));
+}
+
+fn copy_audio_configuration(configuration: &PluginAudioConfiguration) -> PluginAudioConfiguration {
+    PluginAudioConfiguration {
+        sample_rate: configuration.sample_rate,
+        frames_count_range: configuration.frames_count_range.clone(),
+    }
 }
@@ -0,0 +1,186 @@
+use std::f64::consts::PI;
+
+/// Half the length of the interpolation kernel, in frames.
+const HALF_TAPS: usize = 16;
+const TAPS: usize = HALF_TAPS * 2;
+/// Kernel positions between two input frames, the rest is interpolated.
+const PHASES: usize = 128;
+/// How many input frames are pulled at once.
+const CHUNK_FRAMES: usize = 64;
+/// Keeps the transition band below the lower Nyquist frequency.
+const CUTOFF_MARGIN: f64 = 0.95;
+
+/// Converts interleaved audio between two sample rates with a windowed sinc
+/// kernel. Input is pulled on demand, so it can sit in front of anything that
+/// renders audio in blocks.
+pub struct Resampler {
+    channels: usize,
+    from_rate: u32,
+    to_rate: u32,
+    kernel: Vec<f32>,
+    history: Vec<f32>,
+    chunk: Vec<f32>,
+    /// Input frame in `history` the next output frame comes after.
+    index: usize,
+    /// Distance to that frame, in `1 / to_rate` units.
+    fraction: u32,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, from_rate: u32, to_rate: u32) -> Self {
+        let cutoff = (to_rate as f64 / from_rate as f64).min(1.0) * CUTOFF_MARGIN;
+
+        let kernel = (0..=PHASES)
+            .flat_map(|phase| {
+                (0..TAPS).map(move |tap| {
+                    let distance =
+                        phase as f64 / PHASES as f64 + (HALF_TAPS - 1) as f64 - tap as f64;
+                    (cutoff * sinc(cutoff * distance) * blackman(distance / HALF_TAPS as f64))
+                        as f32
+                })
+            })
+            .collect();
+
+        // The frames before the first one are silent
+        let mut history = Vec::with_capacity((TAPS + CHUNK_FRAMES) * channels);
+        history.resize((HALF_TAPS - 1) * channels, 0.0);
+
+        Self {
+            channels,
+            from_rate,
+            to_rate,
+            kernel,
+            history,
+            chunk: vec![0.0; CHUNK_FRAMES * channels],
+            index: HALF_TAPS - 1,
+            fraction: 0,
+        }
+    }
+
+    /// Fills the interleaved `output`, calling `pull` with an interleaved
+    /// chunk of input to fill whenever more input is needed.
+    pub fn fill(&mut self, output: &mut [f32], mut pull: impl FnMut(&mut [f32])) {
+        let mut coefficients = [0.0; TAPS];
+
+        for frame in output.chunks_exact_mut(self.channels) {
+            while self.index + HALF_TAPS >= self.history.len() / self.channels {
+                // Frames the kernel has moved past aren't needed anymore
+                let consumed = self.index + 1 - HALF_TAPS;
+                self.history.drain(..consumed * self.channels);
+                self.index -= consumed;
+
+                pull(&mut self.chunk);
+                // Capacity is reserved up front, so this doesn't allocate
+                self.history.extend_from_slice(&self.chunk);
+            }
+
+            let position = self.fraction as f64 / self.to_rate as f64 * PHASES as f64;
+            let phase = position as usize;
+            let weight = (position - phase as f64) as f32;
+            let row = &self.kernel[phase * TAPS..(phase + 2) * TAPS];
+
+            for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+                *coefficient = row[tap] + (row[TAPS + tap] - row[tap]) * weight;
+            }
+
+            let first = (self.index + 1 - HALF_TAPS) * self.channels;
+            let window = &self.history[first..first + TAPS * self.channels];
+
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = coefficients
+                    .iter()
+                    .zip(window[channel..].iter().step_by(self.channels))
+                    .map(|(coefficient, input)| coefficient * input)
+                    .sum();
+            }
+
+            self.fraction += self.from_rate;
+            self.index += (self.fraction / self.to_rate) as usize;
+            self.fraction %= self.to_rate;
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Spans `-1.0..=1.0`.
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+
+    let x = (x + 1.0) / 2.0;
+    0.42 - 0.5 * (2.0 * PI * x).cos() + 0.08 * (4.0 * PI * x).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATES: [(u32, u32); 5] = [
+        (44_100, 48_000),
+        (48_000, 44_100),
+        (48_000, 96_000),
+        (96_000, 48_000),
+        (48_000, 48_000),
+    ];
+
+    #[test]
+    fn pulls_input_at_the_rate_ratio() {
+        for (from_rate, to_rate) in RATES {
+            let mut resampler = Resampler::new(2, from_rate, to_rate);
+            let mut output = vec![0.0; 2 * 441];
+            let mut pulled_frames = 0;
+
+            for _ in 0..1000 {
+                resampler.fill(&mut output, |chunk| pulled_frames += chunk.len() / 2);
+            }
+
+            let output_frames = 1000 * 441;
+            let expected = output_frames as f64 * from_rate as f64 / to_rate as f64;
+            // Up to a chunk pulled ahead, and the kernel's delay
+            assert!(
+                (pulled_frames as f64 - expected).abs() <= (CHUNK_FRAMES + HALF_TAPS) as f64,
+                "{from_rate} -> {to_rate}: pulled {pulled_frames} frames, expected {expected}",
+            );
+        }
+    }
+
+    #[test]
+    fn passes_dc_at_unity_gain() {
+        for (from_rate, to_rate) in RATES {
+            let mut resampler = Resampler::new(2, from_rate, to_rate);
+            let mut output = vec![0.0; 2 * 1000];
+
+            resampler.fill(&mut output, |chunk| chunk.fill(1.0));
+
+            // Past the silent frames the kernel starts with
+            for sample in &output[2 * 100..] {
+                assert!(
+                    (sample - 1.0).abs() < 1e-3,
+                    "{from_rate} -> {to_rate}: {sample}",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn never_grows_the_history() {
+        for (from_rate, to_rate) in RATES {
+            let mut resampler = Resampler::new(2, from_rate, to_rate);
+            let capacity = resampler.history.capacity();
+
+            for frames in [1, 7, 64, 65, 500, 3, 1024, 0, 31] {
+                let mut output = vec![0.0; 2 * frames];
+                resampler.fill(&mut output, |chunk| chunk.fill(0.5));
+                assert_eq!(resampler.history.capacity(), capacity);
+            }
+        }
+    }
+}
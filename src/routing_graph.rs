@@ -0,0 +1,538 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{
+    execution_plan::{ExecutionPlan, PlanStep, StepKind},
+    plugin_processor::PluginId,
+};
+
+pub type NodeId = usize;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Input,
+    Output,
+    Bus,
+    Plugin(PluginId),
+}
+
+pub struct Node {
+    pub id: NodeId,
+    pub kind: NodeKind,
+}
+
+/// Audio flowing from one node into another, scaled by `gain`.
+pub struct Edge {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub gain: f32,
+}
+
+/// Audio from a node fed into a non-main input port of a plugin node.
+pub struct Sidechain {
+    pub from: NodeId,
+    pub to: NodeId,
+    /// Index of the plugin's input port.
+    pub port: usize,
+}
+
+#[derive(Debug)]
+pub enum RoutingError {
+    UnknownNode(NodeId),
+    InvalidEdge(NodeId, NodeId),
+    NotAPlugin(NodeId),
+    Cycle,
+}
+
+impl Display for RoutingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownNode(id) => write!(f, "There's no node #{id}"),
+            Self::InvalidEdge(from, to) => write!(f, "Node #{from} can't feed node #{to}"),
+            Self::NotAPlugin(id) => write!(f, "Node #{id} isn't a plugin"),
+            Self::Cycle => write!(f, "The connection would create a feedback loop"),
+        }
+    }
+}
+
+impl Error for RoutingError {}
+
+/// Nodes connected by gain edges. Every node sums whatever flows into it,
+/// plugin nodes then process that sum in place.
+pub struct RoutingGraph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+    sidechains: Vec<Sidechain>,
+    next_node_id: NodeId,
+}
+
+impl Default for RoutingGraph {
+    fn default() -> Self {
+        Self {
+            nodes: vec![
+                Node {
+                    id: Self::INPUT,
+                    kind: NodeKind::Input,
+                },
+                Node {
+                    id: Self::OUTPUT,
+                    kind: NodeKind::Output,
+                },
+            ],
+            edges: vec![],
+            sidechains: vec![],
+            next_node_id: 2,
+        }
+    }
+}
+
+impl RoutingGraph {
+    pub const INPUT: NodeId = 0;
+    pub const OUTPUT: NodeId = 1;
+
+    /// A linear chain: input -> plugins in the given order -> output.
+    pub fn chain(plugins: &[PluginId]) -> Self {
+        let mut graph = Self::default();
+        let mut previous = Self::INPUT;
+
+        for plugin_id in plugins {
+            let node = graph.add_plugin(*plugin_id);
+            graph.edges.push(Edge {
+                from: previous,
+                to: node,
+                gain: 1.0,
+            });
+            previous = node;
+        }
+
+        graph.edges.push(Edge {
+            from: previous,
+            to: Self::OUTPUT,
+            gain: 1.0,
+        });
+
+        graph
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    pub fn edges_mut(&mut self) -> &mut [Edge] {
+        &mut self.edges
+    }
+
+    pub fn sidechains(&self) -> &[Sidechain] {
+        &self.sidechains
+    }
+
+    /// The node feeding the given input port of a plugin node.
+    pub fn sidechain(&self, to: NodeId, port: usize) -> Option<NodeId> {
+        self.sidechains
+            .iter()
+            .find(|sidechain| sidechain.to == to && sidechain.port == port)
+            .map(|sidechain| sidechain.from)
+    }
+
+    pub fn add_bus(&mut self) -> NodeId {
+        self.add_node(NodeKind::Bus)
+    }
+
+    /// A plugin has a single processor, so it can only appear once.
+    pub fn add_plugin(&mut self, plugin_id: PluginId) -> NodeId {
+        match self.plugin_node(plugin_id) {
+            Some(id) => id,
+            None => self.add_node(NodeKind::Plugin(plugin_id)),
+        }
+    }
+
+    pub fn plugin_node(&self, plugin_id: PluginId) -> Option<NodeId> {
+        self.nodes
+            .iter()
+            .find(|node| node.kind == NodeKind::Plugin(plugin_id))
+            .map(|node| node.id)
+    }
+
+    fn add_node(&mut self, kind: NodeKind) -> NodeId {
+        let id = self.next_node_id;
+        self.next_node_id += 1;
+        self.nodes.push(Node { id, kind });
+        id
+    }
+
+    /// The input and output nodes are always kept.
+    pub fn remove_node(&mut self, id: NodeId) {
+        if id == Self::INPUT || id == Self::OUTPUT {
+            return;
+        }
+
+        self.nodes.retain(|node| node.id != id);
+        self.edges.retain(|edge| edge.from != id && edge.to != id);
+        self.sidechains
+            .retain(|sidechain| sidechain.from != id && sidechain.to != id);
+    }
+
+    pub fn remove_plugin(&mut self, plugin_id: PluginId) {
+        if let Some(id) = self.plugin_node(plugin_id) {
+            self.remove_node(id);
+        }
+    }
+
+    pub fn connect(&mut self, from: NodeId, to: NodeId, gain: f32) -> Result<(), RoutingError> {
+        let from_kind = self.kind(from)?;
+        let to_kind = self.kind(to)?;
+
+        if from == to || from_kind == NodeKind::Output || to_kind == NodeKind::Input {
+            return Err(RoutingError::InvalidEdge(from, to));
+        }
+
+        if let Some(edge) = self
+            .edges
+            .iter_mut()
+            .find(|edge| edge.from == from && edge.to == to)
+        {
+            edge.gain = gain;
+            return Ok(());
+        }
+
+        self.edges.push(Edge { from, to, gain });
+
+        if let Err(err) = self.sorted() {
+            self.edges.pop();
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, from: NodeId, to: NodeId) {
+        self.edges
+            .retain(|edge| !(edge.from == from && edge.to == to));
+    }
+
+    /// Feeds an input port of a plugin node from another node, or leaves it
+    /// silent with `None`. Like edges, sidechains can't form a loop.
+    pub fn set_sidechain(
+        &mut self,
+        to: NodeId,
+        port: usize,
+        from: Option<NodeId>,
+    ) -> Result<(), RoutingError> {
+        if !matches!(self.kind(to)?, NodeKind::Plugin(_)) {
+            return Err(RoutingError::NotAPlugin(to));
+        }
+
+        if let Some(from) = from {
+            if from == to || self.kind(from)? == NodeKind::Output {
+                return Err(RoutingError::InvalidEdge(from, to));
+            }
+        }
+
+        let previous = self
+            .sidechains
+            .iter()
+            .position(|sidechain| sidechain.to == to && sidechain.port == port)
+            .map(|index| self.sidechains.remove(index));
+
+        let Some(from) = from else {
+            return Ok(());
+        };
+
+        self.sidechains.push(Sidechain { from, to, port });
+
+        if let Err(err) = self.sorted() {
+            self.sidechains.pop();
+            self.sidechains.extend(previous);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Like `set_sidechain`, for plugins addressed by id. They're added as
+    /// nodes if they aren't in the graph yet.
+    pub fn set_plugin_sidechain(
+        &mut self,
+        plugin_id: PluginId,
+        port: usize,
+        from: Option<NodeKind>,
+    ) -> Result<(), RoutingError> {
+        let to = self.add_plugin(plugin_id);
+        let from = match from {
+            Some(NodeKind::Plugin(from_plugin_id)) => Some(self.add_plugin(from_plugin_id)),
+            Some(kind) => self
+                .nodes
+                .iter()
+                .find(|node| node.kind == kind)
+                .map(|node| node.id),
+            None => None,
+        };
+
+        self.set_sidechain(to, port, from)
+    }
+
+    /// Every node that has to be processed before another one.
+    fn dependencies(&self) -> impl Iterator<Item = (NodeId, NodeId)> + '_ {
+        self.edges.iter().map(|edge| (edge.from, edge.to)).chain(
+            self.sidechains
+                .iter()
+                .map(|sidechain| (sidechain.from, sidechain.to)),
+        )
+    }
+
+    fn kind(&self, id: NodeId) -> Result<NodeKind, RoutingError> {
+        self.nodes
+            .iter()
+            .find(|node| node.id == id)
+            .map(|node| node.kind)
+            .ok_or(RoutingError::UnknownNode(id))
+    }
+
+    /// Kahn's algorithm: indices into `nodes`, every node after all of its
+    /// sources.
+    fn sorted(&self) -> Result<Vec<usize>, RoutingError> {
+        let index_of = |id: NodeId| self.nodes.iter().position(|node| node.id == id);
+
+        let mut incoming = vec![0; self.nodes.len()];
+        for (_, to) in self.dependencies() {
+            incoming[index_of(to).ok_or(RoutingError::UnknownNode(to))?] += 1;
+        }
+
+        let mut ready: Vec<_> = (0..self.nodes.len())
+            .filter(|index| incoming[*index] == 0)
+            .collect();
+        let mut sorted = Vec::with_capacity(self.nodes.len());
+
+        while let Some(index) = ready.pop() {
+            sorted.push(index);
+
+            for (_, to) in self
+                .dependencies()
+                .filter(|(from, _)| *from == self.nodes[index].id)
+            {
+                let to = index_of(to).ok_or(RoutingError::UnknownNode(to))?;
+                incoming[to] -= 1;
+                if incoming[to] == 0 {
+                    ready.push(to);
+                }
+            }
+        }
+
+        if sorted.len() != self.nodes.len() {
+            return Err(RoutingError::Cycle);
+        }
+
+        Ok(sorted)
+    }
+
+    /// Turns the graph into a flat list of steps with all the buffers
+    /// allocated up front, so the audio thread only has to walk it.
+    pub fn compile(
+        &self,
+        channels: usize,
+        max_frames: usize,
+    ) -> Result<ExecutionPlan, RoutingError> {
+        let sorted = self.sorted()?;
+
+        let steps = sorted
+            .iter()
+            .map(|index| {
+                let node = &self.nodes[*index];
+
+                PlanStep {
+                    kind: match node.kind {
+                        NodeKind::Input => StepKind::Input,
+                        NodeKind::Output => StepKind::Output,
+                        NodeKind::Bus => StepKind::Bus,
+                        NodeKind::Plugin(plugin_id) => StepKind::Plugin(plugin_id),
+                    },
+                    slot: *index,
+                    inputs: self
+                        .edges
+                        .iter()
+                        .filter(|edge| edge.to == node.id)
+                        .filter_map(|edge| {
+                            let from = self.nodes.iter().position(|node| node.id == edge.from)?;
+                            Some((from, edge.gain))
+                        })
+                        .collect(),
+                    sidechains: self
+                        .sidechains
+                        .iter()
+                        .filter(|sidechain| sidechain.to == node.id)
+                        .filter_map(|sidechain| {
+                            let from = self
+                                .nodes
+                                .iter()
+                                .position(|node| node.id == sidechain.from)?;
+                            Some((sidechain.port, from))
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Ok(ExecutionPlan::new(
+            steps,
+            self.nodes.len(),
+            channels,
+            max_frames,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(graph: &RoutingGraph, sorted: &[usize], id: NodeId) -> usize {
+        sorted
+            .iter()
+            .position(|index| graph.nodes[*index].id == id)
+            .unwrap()
+    }
+
+    #[test]
+    fn sorts_sources_first() {
+        let mut graph = RoutingGraph::default();
+        let bus = graph.add_bus();
+        let plugin = graph.add_plugin(7);
+        graph.connect(bus, RoutingGraph::OUTPUT, 1.0).unwrap();
+        graph.connect(plugin, bus, 1.0).unwrap();
+        graph.connect(RoutingGraph::INPUT, plugin, 1.0).unwrap();
+
+        let sorted = graph.sorted().unwrap();
+
+        assert_eq!(sorted.len(), 4);
+        assert!(position(&graph, &sorted, RoutingGraph::INPUT) < position(&graph, &sorted, plugin));
+        assert!(position(&graph, &sorted, plugin) < position(&graph, &sorted, bus));
+        assert!(position(&graph, &sorted, bus) < position(&graph, &sorted, RoutingGraph::OUTPUT));
+    }
+
+    #[test]
+    fn rejects_invalid_edges() {
+        let mut graph = RoutingGraph::default();
+        let bus = graph.add_bus();
+
+        assert!(matches!(
+            graph.connect(RoutingGraph::OUTPUT, bus, 1.0),
+            Err(RoutingError::InvalidEdge(..))
+        ));
+        assert!(matches!(
+            graph.connect(bus, RoutingGraph::INPUT, 1.0),
+            Err(RoutingError::InvalidEdge(..))
+        ));
+        assert!(matches!(
+            graph.connect(bus, bus, 1.0),
+            Err(RoutingError::InvalidEdge(..))
+        ));
+        assert!(matches!(
+            graph.connect(bus, 42, 1.0),
+            Err(RoutingError::UnknownNode(42))
+        ));
+        assert!(graph.edges().is_empty());
+    }
+
+    #[test]
+    fn rolls_back_a_connection_that_makes_a_cycle() {
+        let mut graph = RoutingGraph::default();
+        let first = graph.add_plugin(1);
+        let second = graph.add_plugin(2);
+        let bus = graph.add_bus();
+        graph.connect(first, second, 1.0).unwrap();
+        graph.connect(second, bus, 1.0).unwrap();
+
+        assert!(matches!(
+            graph.connect(bus, first, 1.0),
+            Err(RoutingError::Cycle)
+        ));
+        assert_eq!(graph.edges().len(), 2);
+        assert!(graph.sorted().is_ok());
+    }
+
+    #[test]
+    fn rolls_back_a_sidechain_that_makes_a_cycle() {
+        let mut graph = RoutingGraph::default();
+        let first = graph.add_plugin(1);
+        let second = graph.add_plugin(2);
+        graph.connect(first, second, 1.0).unwrap();
+        graph
+            .set_sidechain(first, 1, Some(RoutingGraph::INPUT))
+            .unwrap();
+
+        assert!(matches!(
+            graph.set_sidechain(first, 1, Some(second)),
+            Err(RoutingError::Cycle)
+        ));
+        // The sidechain it would have replaced is kept
+        assert_eq!(graph.sidechain(first, 1), Some(RoutingGraph::INPUT));
+        assert_eq!(graph.sidechains().len(), 1);
+    }
+
+    #[test]
+    fn sidechains_only_feed_plugins() {
+        let mut graph = RoutingGraph::default();
+        let bus = graph.add_bus();
+        let plugin = graph.add_plugin(1);
+
+        assert!(matches!(
+            graph.set_sidechain(bus, 1, Some(RoutingGraph::INPUT)),
+            Err(RoutingError::NotAPlugin(_))
+        ));
+        assert!(matches!(
+            graph.set_sidechain(plugin, 1, Some(RoutingGraph::OUTPUT)),
+            Err(RoutingError::InvalidEdge(..))
+        ));
+        assert!(graph.sidechains().is_empty());
+    }
+
+    #[test]
+    fn compiles_a_chain_in_order() {
+        let graph = RoutingGraph::chain(&[10, 20]);
+        let input = graph
+            .nodes
+            .iter()
+            .position(|node| node.kind == NodeKind::Input);
+        let first = graph.plugin_node(10).unwrap();
+        let second = graph.plugin_node(20).unwrap();
+
+        let plan = graph.compile(2, 64).unwrap();
+        let steps = plan.steps();
+
+        assert_eq!(steps.len(), 4);
+        assert!(matches!(steps[0].kind, StepKind::Input));
+        assert!(matches!(steps[1].kind, StepKind::Plugin(10)));
+        assert!(matches!(steps[2].kind, StepKind::Plugin(20)));
+        assert!(matches!(steps[3].kind, StepKind::Output));
+
+        assert!(steps[0].inputs.is_empty());
+        assert_eq!(Some(steps[0].slot), input);
+        assert_eq!(steps[1].inputs, [(steps[0].slot, 1.0)]);
+        assert_eq!(steps[2].inputs, [(steps[1].slot, 1.0)]);
+        assert_eq!(steps[3].inputs, [(steps[2].slot, 1.0)]);
+        assert_eq!(graph.nodes[steps[1].slot].id, first);
+        assert_eq!(graph.nodes[steps[2].slot].id, second);
+    }
+
+    #[test]
+    fn compiles_sidechains_to_their_source_slot() {
+        let mut graph = RoutingGraph::chain(&[10, 20]);
+        let second = graph.plugin_node(20).unwrap();
+        graph
+            .set_sidechain(second, 1, Some(RoutingGraph::INPUT))
+            .unwrap();
+
+        let plan = graph.compile(2, 64).unwrap();
+        let steps = plan.steps();
+        let input_slot = steps[0].slot;
+
+        assert!(steps[1].sidechains.is_empty());
+        assert_eq!(steps[2].sidechains, [(1, input_slot)]);
+    }
+}
@@ -0,0 +1,108 @@
+use cpal::{Data, SampleFormat};
+
+/// Renders f32 audio into the stream's native sample format. Integer formats
+/// get TPDF dither before they're quantized.
+pub struct SampleWriter {
+    scratch: Vec<f32>,
+    dither: Dither,
+}
+
+impl SampleWriter {
+    /// `scratch_len` has to be a multiple of the channel count.
+    pub fn new(scratch_len: usize) -> Self {
+        Self {
+            scratch: vec![0.0; scratch_len],
+            dither: Dither::default(),
+        }
+    }
+
+    pub fn write(&mut self, data: &mut Data, mut render: impl FnMut(&mut [f32])) {
+        match data.sample_format() {
+            SampleFormat::F32 => {
+                if let Some(output) = data.as_slice_mut::<f32>() {
+                    render(output);
+                }
+            }
+            SampleFormat::F64 => {
+                self.convert(data.as_slice_mut(), render, |sample, _| sample as f64)
+            }
+            SampleFormat::I8 => self.convert(data.as_slice_mut(), render, |sample, dither| {
+                dither.quantize(sample, 8) as i8
+            }),
+            SampleFormat::I16 => self.convert(data.as_slice_mut(), render, |sample, dither| {
+                dither.quantize(sample, 16) as i16
+            }),
+            SampleFormat::I32 => self.convert(data.as_slice_mut(), render, |sample, dither| {
+                dither.quantize(sample, 32) as i32
+            }),
+            SampleFormat::I64 => self.convert(data.as_slice_mut(), render, |sample, dither| {
+                dither.quantize(sample, 64)
+            }),
+            SampleFormat::U8 => self.convert(data.as_slice_mut(), render, |sample, dither| {
+                (dither.quantize(sample, 8) + (1 << 7)) as u8
+            }),
+            SampleFormat::U16 => self.convert(data.as_slice_mut(), render, |sample, dither| {
+                (dither.quantize(sample, 16) + (1 << 15)) as u16
+            }),
+            SampleFormat::U32 => self.convert(data.as_slice_mut(), render, |sample, dither| {
+                (dither.quantize(sample, 32) + (1 << 31)) as u32
+            }),
+            SampleFormat::U64 => self.convert(data.as_slice_mut(), render, |sample, dither| {
+                (dither.quantize(sample, 64) as u64) ^ (1 << 63)
+            }),
+            // Not something cpal can open yet
+            _ => data.bytes_mut().fill(0),
+        }
+    }
+
+    fn convert<T>(
+        &mut self,
+        output: Option<&mut [T]>,
+        mut render: impl FnMut(&mut [f32]),
+        convert: impl Fn(f32, &mut Dither) -> T,
+    ) {
+        let Some(output) = output else {
+            return;
+        };
+
+        for output in output.chunks_mut(self.scratch.len()) {
+            let scratch = &mut self.scratch[..output.len()];
+            render(scratch);
+
+            for (output, sample) in output.iter_mut().zip(scratch.iter()) {
+                *output = convert(*sample, &mut self.dither);
+            }
+        }
+    }
+}
+
+/// Triangular noise of one least significant bit, from a xorshift generator
+/// so the audio thread doesn't need anything else.
+struct Dither {
+    state: u32,
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self { state: 0x9E37_79B9 }
+    }
+}
+
+impl Dither {
+    fn next(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f64 / u32::MAX as f64
+    }
+
+    /// Scales to a signed integer of `bits` bits, dithers and rounds it.
+    fn quantize(&mut self, sample: f32, bits: u32) -> i64 {
+        let scale = (1u64 << (bits - 1)) as f64;
+        let noise = self.next() - self.next();
+
+        (sample as f64 * scale + noise)
+            .round()
+            .clamp(-scale, scale - 1.0) as i64
+    }
+}
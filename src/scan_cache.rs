@@ -0,0 +1,295 @@
+use std::{
+    collections::HashMap,
+    env,
+    io::Read,
+    path::Path,
+    process::{self, Command, Stdio},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use crate::plugin_bundle::{describe_bundle, PluginDescription};
+
+/// The command line flag that turns the binary into a bundle prober.
+pub const PROBE_ARG: &str = "--probe-bundle";
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub enum ProbeResult {
+    Plugins(Vec<PluginDescription>),
+    /// The bundle failed to load or crashed the prober. It stays blocked
+    /// until the file changes or the cache is cleared.
+    Failed(String),
+}
+
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+struct CachedBundle {
+    modified: u64,
+    size: u64,
+    result: ProbeResult,
+}
+
+/// Probe results of every bundle seen so far, persisted with the app state.
+#[derive(Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct ScanCache {
+    bundles: HashMap<String, CachedBundle>,
+}
+
+impl ScanCache {
+    /// Returns the cached result if the bundle hasn't changed since it was
+    /// probed, and probes it in a child process otherwise.
+    pub fn probe(&mut self, path: &Path) -> ProbeResult {
+        let key = path.display().to_string();
+        let Some((modified, size)) = file_stamp(path) else {
+            return ProbeResult::Failed("Can't read the bundle metadata".to_owned());
+        };
+
+        if let Some(cached) = self.bundles.get(&key) {
+            if cached.modified == modified && cached.size == size {
+                return cached.result.clone();
+            }
+        }
+
+        let result = match probe_in_child(path) {
+            Ok(result) => result,
+            // Not the bundle's fault, so it's probed again next time
+            Err(reason) => return ProbeResult::Failed(reason),
+        };
+        self.bundles.insert(
+            key,
+            CachedBundle {
+                modified,
+                size,
+                result: result.clone(),
+            },
+        );
+
+        result
+    }
+
+    pub fn clear(&mut self) {
+        self.bundles.clear();
+    }
+
+    /// Drops bundles that weren't found during the last scan.
+    pub fn retain(&mut self, paths: &[String]) {
+        self.bundles.retain(|path, _| paths.contains(path));
+    }
+}
+
+fn file_stamp(path: &Path) -> Option<(u64, u64)> {
+    let metadata = path.metadata().ok()?;
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+
+    Some((modified, metadata.len()))
+}
+
+/// Fails only if the prober itself couldn't be run, the bundle's own
+/// failures are a `ProbeResult`.
+fn probe_in_child(path: &Path) -> Result<ProbeResult, String> {
+    let mut child = match env::current_exe().and_then(|exe| {
+        Command::new(exe)
+            .arg(PROBE_ARG)
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+    }) {
+        Ok(child) => child,
+        Err(err) => return Err(format!("Can't start the prober: {err}")),
+    };
+
+    // Read while the child runs, it blocks once the pipe is full
+    let reader = child.stdout.take().map(|mut stdout| {
+        thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stdout.read_to_string(&mut output);
+            output
+        })
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() > PROBE_TIMEOUT => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Ok(ProbeResult::Failed("The prober timed out".to_owned()));
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(err) => return Err(format!("Lost the prober: {err}")),
+        }
+    };
+
+    let output = reader
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default();
+
+    if !status.success() {
+        let reason = output
+            .lines()
+            .find_map(|line| line.strip_prefix("error\t"))
+            .map(unescape)
+            .unwrap_or_else(|| format!("The prober exited with {status}"));
+        return Ok(ProbeResult::Failed(reason));
+    }
+
+    Ok(ProbeResult::Plugins(
+        output.lines().filter_map(parse_description).collect(),
+    ))
+}
+
+/// Entry point of the child process: loads the bundle and prints one line
+/// per plugin, then exits. Never returns.
+///
+/// Fields are separated by tabs, the features being the trailing ones.
+pub fn run_prober(path: &str) -> ! {
+    match describe_bundle(path) {
+        Ok(descriptions) => {
+            for description in descriptions {
+                println!("{}", format_description(&description));
+            }
+            process::exit(0)
+        }
+        Err(err) => {
+            println!("error\t{}", escape(&err.to_string()));
+            process::exit(1)
+        }
+    }
+}
+
+fn format_description(description: &PluginDescription) -> String {
+    let mut line = format!(
+        "plugin\t{}\t{}\t{}\t{}",
+        escape(&description.id),
+        escape(&description.name),
+        escape(&description.vendor),
+        escape(&description.version),
+    );
+
+    for feature in &description.features {
+        line.push('\t');
+        line.push_str(&escape(feature));
+    }
+
+    line
+}
+
+fn parse_description(line: &str) -> Option<PluginDescription> {
+    let mut fields = line.strip_prefix("plugin\t")?.split('\t').map(unescape);
+
+    Some(PluginDescription {
+        id: fields.next()?,
+        name: fields.next()?,
+        vendor: fields.next()?,
+        version: fields.next()?,
+        features: fields.collect(),
+    })
+}
+
+/// Keeps a value on one line and free of tabs, `unescape` gives it back.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn description(id: &str, features: &[&str]) -> PluginDescription {
+        PluginDescription {
+            id: id.to_owned(),
+            name: "Synth\tOne".to_owned(),
+            vendor: "Vendor\r\nInc.".to_owned(),
+            version: "1.0".to_owned(),
+            features: features.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn round_trips_descriptions() {
+        for description in [
+            description("com.vendor.synth", &["instrument", "synthesizer"]),
+            description("com.vendor\t\\synth\n", &["with, comma", "tab\tbed", ""]),
+            description("", &[]),
+        ] {
+            let line = format_description(&description);
+            assert!(!line.contains('\n') && !line.contains('\r'));
+            assert_eq!(parse_description(&line), Some(description));
+        }
+    }
+
+    #[test]
+    fn round_trips_escapes() {
+        for value in [
+            "",
+            "plain",
+            "a\\tb",
+            "\\",
+            "tab\there",
+            "line\nbreak\r",
+            "\\\\n",
+        ] {
+            let escaped = escape(value);
+            assert!(!escaped.contains(['\t', '\n', '\r']));
+            assert_eq!(unescape(&escaped), value);
+        }
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        assert_eq!(parse_description("error\tFailed"), None);
+        assert_eq!(parse_description("plugin\tid\tname\tvendor"), None);
+        assert_eq!(parse_description(""), None);
+    }
+
+    #[test]
+    fn reads_descriptions_without_features() {
+        let description = parse_description("plugin\tid\tname\tvendor\t1.0").unwrap();
+        assert_eq!(description.id, "id");
+        assert_eq!(description.version, "1.0");
+        assert!(description.features.is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    plugin_bundle::PluginDescription,
+    scan_cache::{ProbeResult, ScanCache},
+};
+
+pub struct CatalogEntry {
+    pub path: String,
+    pub description: PluginDescription,
+}
+
+#[derive(Default)]
+pub struct PluginCatalog {
+    pub entries: Vec<CatalogEntry>,
+    pub failed: Vec<(String, String)>,
+}
+
+impl PluginCatalog {
+    /// Bundles are only probed when they aren't in the cache or have changed
+    /// since; failed ones are listed without being probed again.
+    pub fn scan(cache: &mut ScanCache) -> Self {
+        let mut catalog = Self::default();
+        let mut found = vec![];
+
+        for bundle_path in find_bundles() {
+            let path = bundle_path.display().to_string();
+
+            match cache.probe(&bundle_path) {
+                ProbeResult::Plugins(descriptions) => {
+                    catalog
+                        .entries
+                        .extend(descriptions.into_iter().map(|description| CatalogEntry {
+                            path: path.clone(),
+                            description,
+                        }))
+                }
+                ProbeResult::Failed(reason) => catalog.failed.push((path.clone(), reason)),
+            }
+
+            found.push(path);
+        }
+
+        cache.retain(&found);
+
+        catalog
+            .entries
+            .sort_by(|a, b| a.description.name.cmp(&b.description.name));
+
+        catalog
+    }
+}
+
+/// A scan running on its own thread, since probing a bundle can take up to
+/// the probe timeout. It works on a copy of the cache and hands it back.
+pub struct CatalogScan {
+    handle: JoinHandle<(PluginCatalog, ScanCache)>,
+}
+
+impl CatalogScan {
+    /// `on_done` is called from the scan thread, to wake up the UI.
+    pub fn start(mut cache: ScanCache, on_done: impl FnOnce() + Send + 'static) -> Self {
+        let handle = thread::spawn(move || {
+            let catalog = PluginCatalog::scan(&mut cache);
+            on_done();
+            (catalog, cache)
+        });
+
+        Self { handle }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Blocks until the scan is done. `None` if it panicked.
+    pub fn join(self) -> Option<(PluginCatalog, ScanCache)> {
+        self.handle.join().ok()
+    }
+}
+
+/// Directories CLAP bundles are looked up in, following the CLAP spec for
+/// Linux: `CLAP_PATH` entries first, then `~/.clap` and `/usr/lib/clap`.
+pub fn search_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+
+    if let Some(clap_path) = env::var_os("CLAP_PATH") {
+        paths.extend(env::split_paths(&clap_path));
+    }
+
+    if let Some(home) = env::var_os("HOME") {
+        paths.push(Path::new(&home).join(".clap"));
+    }
+
+    paths.push(PathBuf::from("/usr/lib/clap"));
+
+    paths
+}
+
+pub fn find_bundles() -> Vec<PathBuf> {
+    let mut bundles = vec![];
+
+    for path in search_paths() {
+        collect_bundles(&path, &mut bundles);
+    }
+
+    bundles.sort();
+    bundles.dedup();
+    bundles
+}
+
+fn collect_bundles(dir: &Path, bundles: &mut Vec<PathBuf>) {
+    let Ok(entries) = dir.read_dir() else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "clap")
+        {
+            bundles.push(path);
+        } else if path.is_dir() {
+            collect_bundles(&path, bundles);
+        }
+    }
+}
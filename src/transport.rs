@@ -0,0 +1,339 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+
+use clack_host::{
+    events::event_types::{TransportEvent, TransportFlags},
+    prelude::EventHeader,
+    utils::{BeatTime, SecondsTime},
+};
+
+/// What the user sets, persisted with the app.
+#[derive(Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct TransportSettings {
+    /// In BPM.
+    pub tempo: f64,
+    pub numerator: u16,
+    pub denominator: u16,
+    pub is_looping: bool,
+    /// In beats.
+    pub loop_start: f64,
+    pub loop_end: f64,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        Self {
+            tempo: 120.0,
+            numerator: 4,
+            denominator: 4,
+            is_looping: false,
+            loop_start: 0.0,
+            loop_end: 16.0,
+        }
+    }
+}
+
+impl TransportSettings {
+    pub fn beats_per_bar(&self) -> f64 {
+        self.numerator as f64 * 4.0 / self.denominator.max(1) as f64
+    }
+
+    pub fn beats_to_seconds(&self, beats: f64) -> f64 {
+        beats * 60.0 / self.tempo
+    }
+
+    fn has_loop(&self) -> bool {
+        self.is_looping && self.loop_end > self.loop_start
+    }
+}
+
+pub enum TransportCommand {
+    Play,
+    Stop,
+    /// To a position in beats.
+    Seek(f64),
+    SetSettings(TransportSettings),
+}
+
+/// Where the audio thread's transport is, for the UI to show.
+#[derive(Default)]
+pub struct TransportPosition {
+    beats: AtomicU64,
+    is_playing: AtomicBool,
+}
+
+impl TransportPosition {
+    pub fn beats(&self) -> f64 {
+        f64::from_bits(self.beats.load(Ordering::Relaxed))
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.is_playing.load(Ordering::Relaxed)
+    }
+}
+
+/// The host's song position, moved forward by the audio thread. Loops wrap at
+/// block boundaries.
+pub struct Transport {
+    settings: TransportSettings,
+    is_playing: bool,
+    beats: f64,
+    /// Set when the position doesn't follow from the previous block.
+    has_jumped: bool,
+    position: Arc<TransportPosition>,
+}
+
+impl Transport {
+    pub fn new(position: Arc<TransportPosition>) -> Self {
+        Self {
+            settings: TransportSettings::default(),
+            is_playing: false,
+            beats: 0.0,
+            has_jumped: false,
+            position,
+        }
+    }
+
+    pub fn handle(&mut self, command: TransportCommand) {
+        match command {
+            TransportCommand::Play => self.is_playing = true,
+            TransportCommand::Stop => self.is_playing = false,
+            TransportCommand::Seek(beats) => {
+                self.beats = beats.max(0.0);
+                self.has_jumped = true;
+            }
+            TransportCommand::SetSettings(settings) => self.settings = settings,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.is_playing
+    }
+
+    pub fn has_jumped(&self) -> bool {
+        self.has_jumped
+    }
+
+    pub fn tempo(&self) -> f64 {
+        self.settings.tempo
+    }
+
+    pub fn beats(&self) -> f64 {
+        self.beats
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.settings.beats_to_seconds(self.beats)
+    }
+
+    /// The song position `frames` into the next block.
+    pub fn beats_at(&self, frames: u32, sample_rate: f64) -> f64 {
+        if !self.is_playing {
+            return self.beats;
+        }
+
+        self.beats + frames as f64 / sample_rate * self.settings.tempo / 60.0
+    }
+
+    /// Describes the start of the next block to plugins.
+    pub fn event(&self) -> TransportEvent {
+        let settings = &self.settings;
+
+        let mut flags = TransportFlags::HAS_TEMPO
+            | TransportFlags::HAS_BEATS_TIMELINE
+            | TransportFlags::HAS_SECONDS_TIMELINE
+            | TransportFlags::HAS_TIME_SIGNATURE;
+        if self.is_playing {
+            flags |= TransportFlags::IS_PLAYING;
+        }
+        if settings.has_loop() {
+            flags |= TransportFlags::IS_LOOP_ACTIVE;
+        }
+
+        let beats_per_bar = settings.beats_per_bar();
+        let bar_number = (self.beats / beats_per_bar).floor();
+
+        TransportEvent {
+            header: EventHeader::new(0),
+            flags,
+            song_pos_beats: BeatTime::from_float(self.beats),
+            song_pos_seconds: SecondsTime::from_float(self.seconds()),
+            tempo: settings.tempo,
+            tempo_inc: 0.0,
+            loop_start_beats: BeatTime::from_float(settings.loop_start),
+            loop_end_beats: BeatTime::from_float(settings.loop_end),
+            loop_start_seconds: SecondsTime::from_float(
+                settings.beats_to_seconds(settings.loop_start),
+            ),
+            loop_end_seconds: SecondsTime::from_float(settings.beats_to_seconds(settings.loop_end)),
+            bar_start: BeatTime::from_float(bar_number * beats_per_bar),
+            bar_number: bar_number as i32,
+            time_signature_numerator: settings.numerator,
+            time_signature_denominator: settings.denominator,
+        }
+    }
+
+    /// Moves past a processed block and publishes the new position.
+    pub fn advance(&mut self, frames: usize, sample_rate: f64) {
+        self.has_jumped = false;
+
+        if self.is_playing {
+            let settings = &self.settings;
+            self.beats += frames as f64 / sample_rate * settings.tempo / 60.0;
+
+            if settings.has_loop() && self.beats >= settings.loop_end {
+                let length = settings.loop_end - settings.loop_start;
+                self.beats = settings.loop_start + (self.beats - settings.loop_end) % length;
+                self.has_jumped = true;
+            }
+        }
+
+        self.position
+            .beats
+            .store(self.beats.to_bits(), Ordering::Relaxed);
+        self.position
+            .is_playing
+            .store(self.is_playing, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48_000.0;
+    /// A beat at 120 BPM.
+    const BEAT: usize = 24_000;
+
+    fn transport(settings: TransportSettings) -> (Transport, Arc<TransportPosition>) {
+        let position = Arc::new(TransportPosition::default());
+        let mut transport = Transport::new(position.clone());
+        transport.handle(TransportCommand::SetSettings(settings));
+        (transport, position)
+    }
+
+    fn looping(loop_start: f64, loop_end: f64) -> TransportSettings {
+        TransportSettings {
+            is_looping: true,
+            loop_start,
+            loop_end,
+            ..TransportSettings::default()
+        }
+    }
+
+    #[test]
+    fn converts_beats_to_seconds_and_bars() {
+        let settings = TransportSettings {
+            tempo: 90.0,
+            numerator: 6,
+            denominator: 8,
+            ..TransportSettings::default()
+        };
+
+        assert_eq!(settings.beats_to_seconds(3.0), 2.0);
+        assert_eq!(settings.beats_per_bar(), 3.0);
+        assert_eq!(TransportSettings::default().beats_per_bar(), 4.0);
+    }
+
+    #[test]
+    fn counts_beats_into_the_block_while_playing() {
+        let (mut transport, _) = transport(TransportSettings::default());
+        transport.handle(TransportCommand::Seek(2.0));
+
+        assert_eq!(transport.beats_at(BEAT as u32, SAMPLE_RATE), 2.0);
+
+        transport.handle(TransportCommand::Play);
+        assert_eq!(transport.beats_at(0, SAMPLE_RATE), 2.0);
+        assert_eq!(transport.beats_at(BEAT as u32 / 2, SAMPLE_RATE), 2.5);
+    }
+
+    #[test]
+    fn advances_only_while_playing() {
+        let (mut transport, position) = transport(TransportSettings::default());
+
+        transport.advance(BEAT, SAMPLE_RATE);
+        assert_eq!(transport.beats(), 0.0);
+
+        transport.handle(TransportCommand::Play);
+        transport.advance(BEAT, SAMPLE_RATE);
+        transport.advance(BEAT, SAMPLE_RATE);
+        assert_eq!(transport.beats(), 2.0);
+        assert_eq!(transport.seconds(), 1.0);
+        assert_eq!(position.beats(), 2.0);
+        assert!(position.is_playing());
+        assert!(!transport.has_jumped());
+    }
+
+    #[test]
+    fn seeks_until_the_next_block() {
+        let (mut transport, position) = transport(TransportSettings::default());
+
+        transport.handle(TransportCommand::Seek(-1.0));
+        assert_eq!(transport.beats(), 0.0);
+
+        transport.handle(TransportCommand::Seek(8.0));
+        assert!(transport.has_jumped());
+        assert_eq!(transport.beats(), 8.0);
+
+        transport.advance(BEAT, SAMPLE_RATE);
+        assert!(!transport.has_jumped());
+        assert_eq!(position.beats(), 8.0);
+    }
+
+    #[test]
+    fn wraps_at_the_loop_end() {
+        let (mut transport, _) = transport(looping(4.0, 8.0));
+        transport.handle(TransportCommand::Seek(7.5));
+        transport.handle(TransportCommand::Play);
+
+        transport.advance(BEAT, SAMPLE_RATE);
+        assert_eq!(transport.beats(), 4.5);
+        assert!(transport.has_jumped());
+
+        // Blocks longer than the loop wrap into it too
+        transport.advance(BEAT * 10, SAMPLE_RATE);
+        assert_eq!(transport.beats(), 6.5);
+    }
+
+    #[test]
+    fn ignores_empty_loops() {
+        let (mut transport, _) = transport(looping(4.0, 4.0));
+        transport.handle(TransportCommand::Seek(3.5));
+        transport.handle(TransportCommand::Play);
+
+        transport.advance(BEAT, SAMPLE_RATE);
+        assert_eq!(transport.beats(), 4.5);
+        assert!(!transport.has_jumped());
+    }
+
+    #[test]
+    fn describes_the_block_start() {
+        let (mut transport, _) = transport(TransportSettings {
+            tempo: 90.0,
+            numerator: 3,
+            ..looping(4.0, 8.0)
+        });
+        transport.handle(TransportCommand::Seek(7.0));
+
+        let event = transport.event();
+        assert!(!event.flags.contains(TransportFlags::IS_PLAYING));
+        assert!(event.flags.contains(TransportFlags::IS_LOOP_ACTIVE));
+        assert!(event.flags.contains(TransportFlags::HAS_TEMPO));
+        assert_eq!(event.tempo, 90.0);
+        assert_eq!(event.song_pos_beats, BeatTime::from_float(7.0));
+        assert_eq!(
+            event.song_pos_seconds,
+            SecondsTime::from_float(7.0 * 60.0 / 90.0)
+        );
+        // Bars of three beats
+        assert_eq!(event.bar_number, 2);
+        assert_eq!(event.bar_start, BeatTime::from_float(6.0));
+        assert_eq!(event.time_signature_numerator, 3);
+        assert_eq!(event.loop_end_beats, BeatTime::from_float(8.0));
+
+        transport.handle(TransportCommand::Play);
+        assert!(transport.event().flags.contains(TransportFlags::IS_PLAYING));
+    }
+}